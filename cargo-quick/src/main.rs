@@ -1,16 +1,49 @@
+use std::ffi::OsStr;
+use std::io::ErrorKind;
+use std::process::Command;
+
+const USAGE: &str = "Usage: cargo quick <install|SUBCOMMAND> [ARGS]...\n\
+    \n\
+    SUBCOMMAND is any of cargo-quickbuild's subcommands (see `cargo quick --help`).";
+
 fn main() {
     let mut args: Vec<_> = std::env::args().collect();
-    if args[0] == "quick" {
-        args.remove(0);
+    // `cargo quick foo` runs us as `cargo-quick quick foo`.
+    if args.get(1).map(String::as_str) == Some("quick") {
+        args.remove(1);
     }
-    match args[1].as_str() {
-        "install" => {
-            let exitcode = std::process::Command::new("cargo-quickinstall")
-                .args(&args[2..])
-                .status()
-                .unwrap();
-            std::process::exit(exitcode.code().unwrap_or(1));
+    match args.get(1).map(String::as_str) {
+        Some("install") => exec("cargo-quickinstall", &args[2..]),
+        // cargo-quickbuild lists its own subcommands.
+        Some("-h" | "--help") => {
+            println!("`cargo quick install` runs cargo-quickinstall.");
+            println!("Everything else runs cargo-quickbuild:\n");
+            exec("cargo-quickbuild", ["quick", "--help"])
         }
-        other => todo!("TODO: implement {other}"),
+        Some("-V" | "--version") => {
+            println!("cargo-quick {}", env!("CARGO_PKG_VERSION"));
+        }
+        // Everything else belongs to cargo-quickbuild, which reports unknown subcommands itself.
+        // It adjusts its usage messages when it is told that it is being run as `cargo quick`.
+        Some(_) => exec(
+            "cargo-quickbuild",
+            std::iter::once("quick").chain(args[1..].iter().map(String::as_str)),
+        ),
+        None => {
+            eprintln!("{USAGE}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Run `program` and exit with its exit code.
+fn exec(program: &str, args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> ! {
+    match Command::new(program).args(args).status() {
+        Ok(status) => std::process::exit(status.code().unwrap_or(1)),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            eprintln!("error: {program} not found, install it with `cargo install {program}`");
+        }
+        Err(e) => eprintln!("error: failed to run {program}: {e}"),
     }
+    std::process::exit(1);
 }
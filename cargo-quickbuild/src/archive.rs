@@ -1,30 +1,90 @@
-use std::collections::btree_map;
-use std::fs::File;
-use std::io::Read;
-use std::path::Path;
-use std::{collections::BTreeMap, path::PathBuf};
-
-use anyhow::{Context, Ok, Result};
+use std::collections::BTreeMap;
+use std::fs::{File, Permissions};
+use std::io::{self, ErrorKind, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
+use anyhow::{Context, Result};
+use crypto_hash::{Algorithm, Hasher};
 use filetime::FileTime;
-use tar::{Archive, Builder, Entry, EntryType};
-
-use crate::pax::{BuilderExt, PaxBuilder};
-
-pub fn tar_target_dir(
-    scratch_dir_path: std::path::PathBuf,
-    file: File,
-    file_timestamps_to_exclude: &BTreeMap<PathBuf, FileTime>,
-) -> Result<()> {
-    // FIXME: each tarball contains duplicates of all of the dependencies that we just unpacked already
-    // Either inline whatever append_dir_all() is doing and add filtering, or delete files before making the tarball
-    let mut tar = Builder::new(file);
-    let mut problem = false;
+
+use crate::manifest::{EntryKind, Manifest, ManifestEntry};
+use crate::repo::Repo;
+use crate::util::hash::{hex, sha256_file, sha256_reader};
+use crate::util::reflink::reflink;
+use crate::util::scratch_dir::PathRemap;
+
+/// What we know about a path that was unpacked from a dependency's layer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnpackedEntry {
+    pub mtime: FileTime,
+    /// The SHA-256 of the contents as unpacked (after remapping), or `None` for directories and
+    /// other non-files.
+    pub sha256: Option<String>,
+}
+
+/// How `materialise()` puts files into place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnpackMode {
+    /// Decompress every file from its blob.
+    Copy,
+    /// Keep each layer extracted in the repo dir (see `Repo::extract()`), and reflink files from
+    /// there, falling back to copying them on filesystems that don't support reflinks.
+    Reflink,
+    /// Like `Reflink`, but with hard links, which work on any filesystem. Unpacked files then
+    /// share an inode with the extracted layer, so anything that writes to them in place (rather
+    /// than replacing them) corrupts the layer for every later build.
+    Hardlink,
+}
+
+impl UnpackMode {
+    /// Configured by `CARGO_QUICK_UNPACK_MODE` (`copy`, `reflink` or `hardlink`). Defaults to
+    /// `copy`, which doesn't need any extra disk space.
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("CARGO_QUICK_UNPACK_MODE") {
+            Ok(mode) => mode.parse(),
+            Err(_) => Ok(UnpackMode::Copy),
+        }
+    }
+}
+
+impl FromStr for UnpackMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "copy" => Ok(UnpackMode::Copy),
+            "reflink" => Ok(UnpackMode::Reflink),
+            "hardlink" => Ok(UnpackMode::Hardlink),
+            _ => anyhow::bail!("unknown unpack mode {s:?} (expected copy, reflink or hardlink)"),
+        }
+    }
+}
+
+/// Store everything in `scratch_dir_path/target` that the package's own build created or
+/// modified, rewriting paths in file contents with `remap`. File contents go into the repo as
+/// blobs, and the returned manifest says where they go. Also returns the number of bytes of new
+/// blobs that were stored.
+///
+/// Files that were unpacked from dependencies are left out if their contents are unchanged, even
+/// if cargo touched them, so that each layer only contains its own delta. A build that changes
+/// the contents of a dependency's file is an error, because the layers would then conflict when
+/// they are unpacked together.
+pub fn archive_target_dir(
+    repo: &Repo,
+    scratch_dir_path: &Path,
+    unpacked: &BTreeMap<PathBuf, UnpackedEntry>,
+    remap: &PathRemap,
+) -> Result<(Manifest, u64)> {
+    let mut manifest = Manifest::default();
+    let mut stored_size = 0;
+    let mut modified = Vec::new();
     for entry in walkdir::WalkDir::new(scratch_dir_path.join("target")) {
         let entry = entry?;
         let path = entry.path();
-        let dest = path.strip_prefix(&scratch_dir_path).unwrap();
-        // HACK: don't tar these files.
+        let dest = path.strip_prefix(scratch_dir_path).unwrap();
+        // HACK: don't archive these files.
         if let ".rustc_info.json" | ".cargo-lock" | "CACHEDIR.TAG" =
             path.file_name().unwrap().to_str().unwrap()
         {
@@ -34,182 +94,420 @@ pub fn tar_target_dir(
             log::debug!("skipping timings file: {dest:?}");
             continue;
         }
-        let mtime = FileTime::from_last_modification_time(&entry.metadata()?);
-        match file_timestamps_to_exclude.get(dest) {
-            Some(timestamp) if &mtime == timestamp => {
-                log::debug!("skipping {dest:?} because it already exists");
-            }
-            Some(timestamp) if entry.file_type().is_file() => {
-                let mut contents = String::new();
-                match File::open(entry.path())?.read_to_string(&mut contents) {
-                    core::result::Result::Ok(_) => {
-                        println!("{dest:?}'s mtime has changed from {timestamp:?} to {mtime:?} and it is not a dir. contents:\n{contents:?}");
-                    },
-                    Err(_) => println!("{dest:?}'s mtime has changed from {timestamp:?} to {mtime:?} and it is not a dir. (binary file)"),
+        let metadata = entry.metadata()?;
+        let mtime = FileTime::from_last_modification_time(&metadata);
+        let mode = metadata.permissions().mode() & 0o7777;
+
+        let kind = if metadata.is_file() {
+            if let Some(UnpackedEntry {
+                sha256: Some(sha256),
+                mtime: unpacked_mtime,
+            }) = unpacked.get(dest)
+            {
+                if &sha256_file(path).with_context(|| format!("reading {path:?}"))? == sha256 {
+                    if &mtime != unpacked_mtime {
+                        log::debug!(
+                            "skipping {dest:?}: its mtime has changed from {unpacked_mtime:?} \
+                            to {mtime:?} but its contents are the same"
+                        );
+                    }
+                    continue;
                 }
-                problem = true;
-                append_path_with_mtime(&mut tar, path, dest, mtime)?;
+                modified.push(dest.to_owned());
             }
-            Some(timestamp) => {
-                log::debug!(
-                    "adding {dest:?} because its mtime has changed from {timestamp:?} to {mtime:?}"
-                );
-                append_path_with_mtime(&mut tar, path, dest, mtime)?;
+            // Only files that contain paths need to be held in memory to be rewritten.
+            let mut hasher = Hasher::new(Algorithm::SHA256);
+            let remapped = remap
+                .is_needed(open(path)?, &mut hasher)
+                .with_context(|| format!("reading {path:?}"))?;
+            let (sha256, size) = if remapped {
+                log::debug!("remapped paths in {dest:?}");
+                let mut contents =
+                    std::fs::read(path).with_context(|| format!("reading {path:?}"))?;
+                remap.apply(&mut contents);
+                let sha256 = sha256_reader(contents.as_slice())?;
+                stored_size += repo.write_blob(&sha256, contents.as_slice())?;
+                (sha256, contents.len() as u64)
+            } else {
+                let sha256 = hex(&hasher.finish());
+                stored_size += repo.write_blob(&sha256, open(path)?)?;
+                (sha256, metadata.len())
+            };
+            EntryKind::File {
+                sha256,
+                size,
+                remapped,
             }
-            None => {
-                append_path_with_mtime(&mut tar, path, dest, mtime)?;
+        } else {
+            match unpacked.get(dest) {
+                Some(UnpackedEntry {
+                    mtime: unpacked_mtime,
+                    ..
+                }) if &mtime == unpacked_mtime => {
+                    log::debug!("skipping {dest:?} because it already exists");
+                    continue;
+                }
+                Some(UnpackedEntry {
+                    mtime: unpacked_mtime,
+                    ..
+                }) => {
+                    log::debug!(
+                        "adding {dest:?} because its mtime has changed from {unpacked_mtime:?} to {mtime:?}"
+                    );
+                }
+                None => {}
             }
-        }
+            if metadata.is_dir() {
+                EntryKind::Dir
+            } else if metadata.file_type().is_symlink() {
+                EntryKind::Symlink {
+                    target: std::fs::read_link(path)?,
+                }
+            } else {
+                anyhow::bail!("don't know how to archive {path:?} ({metadata:?})");
+            }
+        };
+        manifest
+            .entries
+            .insert(dest.to_owned(), ManifestEntry { kind, mtime, mode });
     }
-    tar.finish()?;
-    if problem {
-        panic!("Got a timestamp problem. See above logging for details.")
+    if !modified.is_empty() {
+        anyhow::bail!(
+            "the build modified files that were unpacked from dependencies: {modified:?}"
+        );
     }
 
-    Ok(())
+    Ok((manifest, stored_size))
 }
 
-fn append_path_with_mtime(
-    tar: &mut Builder<File>,
-    path: &Path,
-    dest: &Path,
-    mtime: FileTime,
-) -> Result<(), anyhow::Error> {
-    let mut pax = PaxBuilder::new();
-    pax.add(
-        "mtime",
-        &format!("{}.{:09}", mtime.unix_seconds(), mtime.nanoseconds()),
-    );
-    tar.append_pax_extensions(&pax)?;
-
-    tar.append_path_with_name(path, dest)?;
-    Ok(())
-}
-
-/// Originally  copy-pasta of tar-rs's private _unpack() method, but returns the list of paths that have been unpacked.
-/// This was originally proposed as https://github.com/alexcrichton/tar-rs/pull/293 but it was determined
-/// that this isn't something that tar-rs should support directly - we should instead use the tools that
-/// tar-rs provides, and implement it ourselves.
-/// TODO: Also include the folder mtime setting code from https://github.com/alexcrichton/tar-rs/pull/217/
-pub fn tracked_unpack<R: Read>(
-    archive: &mut Archive<R>,
-    dst: &Path,
-) -> Result<BTreeMap<PathBuf, FileTime>> {
-    let mut file_timestamps = BTreeMap::default();
-    // Delay any directory entries until the end (they will be created if needed by
-    // descendants), to ensure that directory permissions do not interfere with descendant
-    // extraction.
-    let mut directories = Vec::new();
-    let mut problem = String::new();
-    for entry in archive.entries()? {
-        let mut file = entry.context("reading entry from archive")?;
-        if file.header().entry_type() == EntryType::Directory {
-            directories.push(file);
-        } else {
-            let mtime = get_high_res_mtime(&mut file)?;
-            let relative_path = file.path()?.to_path_buf();
-            let absolute_path = dst.join(&relative_path);
-
-            insert_timestamp(&mut file_timestamps, &relative_path, mtime)?;
-            if absolute_path.exists() {
-                let mtime_from_disk =
-                    FileTime::from_last_modification_time(&std::fs::metadata(&absolute_path)?);
-                if mtime != mtime_from_disk {
-                    // FIXME: make an extension trait for this conversion
-                    let mtime =
-                        chrono::NaiveDateTime::from_timestamp(mtime.seconds(), mtime.nanoseconds());
-                    let mtime_from_disk = chrono::NaiveDateTime::from_timestamp(
-                        mtime_from_disk.seconds(),
-                        mtime_from_disk.nanoseconds(),
-                    );
-                    let now = chrono::Utc::now();
-
-                    let contents_from_disk = std::fs::read(&absolute_path)?;
-                    let mut contents_from_tarball = Vec::new();
-                    file.read_to_end(&mut contents_from_tarball)?;
-
-                    let contents_differ_message = if contents_from_disk == contents_from_tarball {
-                        String::from("contents identical")
-                    } else {
-                        format!(
-                            "contents differ:\n\
-                        on disk:\n{from_disk}\n\
-                        from tarball:\n{from_tarball}",
-                            from_disk = std::str::from_utf8(&contents_from_disk)
-                                .unwrap_or_else(|_| "<<binary file>>"),
-                            from_tarball = std::str::from_utf8(&contents_from_tarball)
-                                .unwrap_or_else(|_| "<<binary file>>"),
-                        )
-                    };
-
-                    problem = format!(
-                        "{mtime} != {mtime_from_disk} for {relative_path:?} ({absolute_path:?}):\n\
-                        (mtime != on_disk_mtime. now = {now})\n\
-                        {contents_differ_message}",
-                    );
-                    eprintln!("{problem}");
-                }
-            }
-            file.unpack_in(dst)?;
-            filetime::set_file_times(&absolute_path, mtime, mtime)?;
+/// Recreate the files in `manifest` in `target_dir`, with their original mtimes and permissions,
+/// and return what was unpacked. Manifest paths start with `target/`, which is replaced with
+/// `target_dir`, so that layers can be unpacked into a configured target dir (e.g.
+/// `CARGO_TARGET_DIR`).
+///
+/// Unpacking over a target dir that already contains some of the layers is fine, because
+/// `remove_existing()` accepts paths that agree with the manifest.
+///
+/// If `remap` is given, paths in the contents of unpacked files are rewritten with it. If
+/// `extracted` is given, files are linked from there according to `repo.unpack_mode()`, except
+/// for ones that need remapping, which are always copied from their blobs.
+pub fn materialise(
+    repo: &Repo,
+    manifest: &Manifest,
+    extracted: Option<&Path>,
+    target_dir: &Path,
+    remap: Option<&PathRemap>,
+) -> Result<BTreeMap<PathBuf, UnpackedEntry>> {
+    let mut unpacked = BTreeMap::default();
+    // Create directories first, but only set their mtimes and permissions at the end, because
+    // adding things to them would change their mtimes, and permissions might get in the way.
+    for (relative_path, entry) in &manifest.entries {
+        if entry.kind == EntryKind::Dir {
+            let absolute_path = in_target_dir(target_dir, relative_path)?;
+            std::fs::create_dir_all(&absolute_path)
+                .with_context(|| format!("creating {absolute_path:?}"))?;
         }
     }
-    if !problem.is_empty() {
-        anyhow::bail!("{problem}")
+    for (relative_path, entry) in &manifest.entries {
+        let absolute_path = in_target_dir(target_dir, relative_path)?;
+        let sha256 = match &entry.kind {
+            EntryKind::Dir => None,
+            EntryKind::File {
+                sha256, remapped, ..
+            } if extracted.is_some() && !(*remapped && remap.is_some()) => {
+                std::fs::create_dir_all(absolute_path.parent().unwrap())?;
+                if let Some(mtime) = remove_existing(
+                    repo,
+                    relative_path,
+                    &absolute_path,
+                    entry.mtime,
+                    Some(sha256),
+                )? {
+                    log::debug!("kept {relative_path:?}, an identical copy with mtime {mtime}");
+                    let sha256 = Some(sha256.clone());
+                    unpacked.insert(relative_path.clone(), UnpackedEntry { mtime, sha256 });
+                    continue;
+                }
+                link_or_copy(
+                    repo.unpack_mode(),
+                    &extracted.unwrap().join(relative_path),
+                    &absolute_path,
+                )?;
+                filetime::set_file_times(&absolute_path, entry.mtime, entry.mtime)?;
+                Some(sha256.clone())
+            }
+            EntryKind::File {
+                sha256, remapped, ..
+            } => {
+                std::fs::create_dir_all(absolute_path.parent().unwrap())?;
+                // Remapped files don't have the contents that their sha256 says on disk.
+                let unmapped_sha256 =
+                    Some(sha256.as_str()).filter(|_| !(*remapped && remap.is_some()));
+                if let Some(mtime) = remove_existing(
+                    repo,
+                    relative_path,
+                    &absolute_path,
+                    entry.mtime,
+                    unmapped_sha256,
+                )? {
+                    log::debug!("kept {relative_path:?}, an identical copy with mtime {mtime}");
+                    let sha256 = Some(sha256.clone());
+                    unpacked.insert(relative_path.clone(), UnpackedEntry { mtime, sha256 });
+                    continue;
+                }
+                let mut blob = repo.read_blob(sha256)?;
+                let sha256 = match remap {
+                    // Only files that contain paths need to be held in memory to be rewritten.
+                    Some(remap) if *remapped => {
+                        let mut contents = Vec::new();
+                        blob.read_to_end(&mut contents)
+                            .with_context(|| format!("reading the blob for {relative_path:?}"))?;
+                        if remap.apply(&mut contents) {
+                            log::debug!("remapped paths in {absolute_path:?}");
+                        }
+                        std::fs::write(&absolute_path, &contents)
+                            .with_context(|| format!("writing {absolute_path:?}"))?;
+                        sha256_reader(contents.as_slice())?
+                    }
+                    _ => {
+                        let written = File::create(&absolute_path)
+                            .and_then(|mut file| io::copy(&mut blob, &mut file));
+                        if let Err(e) = written {
+                            // Don't leave a truncated file behind.
+                            let _ = std::fs::remove_file(&absolute_path);
+                            return Err(e).with_context(|| format!("writing {absolute_path:?}"));
+                        }
+                        sha256.clone()
+                    }
+                };
+                std::fs::set_permissions(&absolute_path, Permissions::from_mode(entry.mode))?;
+                filetime::set_file_times(&absolute_path, entry.mtime, entry.mtime)?;
+                Some(sha256)
+            }
+            EntryKind::Symlink { target } => {
+                std::fs::create_dir_all(absolute_path.parent().unwrap())?;
+                remove_existing(repo, relative_path, &absolute_path, entry.mtime, None)?;
+                std::os::unix::fs::symlink(target, &absolute_path)
+                    .with_context(|| format!("creating symlink {absolute_path:?}"))?;
+                filetime::set_symlink_file_times(&absolute_path, entry.mtime, entry.mtime)?;
+                None
+            }
+        };
+        log::debug!("unpacked {relative_path:?} with mtime {}", entry.mtime);
+        unpacked.insert(
+            relative_path.clone(),
+            UnpackedEntry {
+                mtime: entry.mtime,
+                sha256,
+            },
+        );
     }
-    for mut dir in directories {
-        let path = dir.path()?.to_path_buf();
-        let mtime = get_high_res_mtime(&mut dir)?;
-        insert_timestamp(&mut file_timestamps, &path, mtime)?;
-        dir.unpack_in(dst)?;
-        filetime::set_file_times(dst.join(dir.path()?), mtime, mtime)?;
+    for (relative_path, entry) in &manifest.entries {
+        if entry.kind == EntryKind::Dir {
+            let absolute_path = in_target_dir(target_dir, relative_path)?;
+            std::fs::set_permissions(&absolute_path, Permissions::from_mode(entry.mode))?;
+            filetime::set_file_times(&absolute_path, entry.mtime, entry.mtime)?;
+        }
     }
-    Ok(file_timestamps)
+    Ok(unpacked)
 }
 
-pub(crate) fn get_high_res_mtime<R: Read>(file: &mut Entry<R>) -> Result<FileTime, anyhow::Error> {
-    let path = file.path().unwrap().into_owned();
-    let low_res_mtime = file.header().mtime().unwrap();
-    let mtime = file
-        .pax_extensions()?
-        .expect("refusing to unpack tarball with low-resolution mtimes")
-        .into_iter()
-        .find(|e| e.as_ref().unwrap().key().as_ref().unwrap() == &"mtime")
-        .map(|x| x.unwrap().value().unwrap())
-        .or({
-            if low_res_mtime == 123456789 {
-                Some("123456789.0")
-            } else {
-                None
-            }
-        })
-        .with_context(|| format!("no high res mtime for {path:?} - low res = {low_res_mtime}",))?;
-    let (seconds, nanos) = mtime.split_once('.').unwrap();
-    let seconds = seconds.parse()?;
-    // right pad with 0s - https://docs.rs/pad/0.1.6/pad/#padding-in-the-stdlib
-    let nanos = format!("{nanos:0<9}")
-        .parse()
-        .with_context(|| format!("parsing {nanos:?}"))?;
-    Ok(FileTime::from_unix_time(seconds, nanos))
+/// Where a manifest path (e.g. `target/debug/foo`) goes when unpacking into `target_dir`.
+fn in_target_dir(target_dir: &Path, relative_path: &Path) -> Result<PathBuf> {
+    let path = relative_path
+        .strip_prefix("target")
+        .with_context(|| format!("{relative_path:?} is not in target/"))?;
+    Ok(target_dir.join(path))
 }
 
-fn insert_timestamp(
-    file_timestamps: &mut BTreeMap<PathBuf, FileTime>,
+fn open(path: &Path) -> Result<File> {
+    File::open(path).with_context(|| format!("opening {path:?}"))
+}
+
+fn link_or_copy(mode: UnpackMode, from: &Path, to: &Path) -> Result<()> {
+    let copy = || {
+        std::fs::copy(from, to).with_context(|| format!("copying {from:?} to {to:?}"))?;
+        Ok(())
+    };
+    let linked = match mode {
+        UnpackMode::Copy => return copy(),
+        UnpackMode::Reflink => reflink(from, to),
+        UnpackMode::Hardlink => std::fs::hard_link(from, to),
+    };
+    match linked {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            log::debug!("copying {from:?} to {to:?} because {mode:?} failed: {e}");
+            copy()
+        }
+    }
+}
+
+/// Make way for a path that we're about to unpack. Layers may only overlap if they agree on the
+/// mtime (e.g. a file that two layers both inherited from a common dependency). If they don't,
+/// the error lists every layer that contains `relative_path`, to help work out which ones
+/// conflict.
+///
+/// The exception is files with the same contents (`sha256`), which happen when layers for
+/// different targets (`--target a --target b`) each built the same host artifact, e.g. the build
+/// script of a package that both depend on. The older copy wins, because each layer's outputs
+/// are newer than its own copy, and so also newer than the oldest one. Returns the mtime of the
+/// existing file if it should be kept.
+///
+/// The old file is removed rather than overwritten, because cargo hard-links build outputs
+/// (e.g. `target/debug/foo` and `target/debug/deps/foo-1234`), and writing through one link
+/// would change the other.
+fn remove_existing(
+    repo: &Repo,
+    relative_path: &Path,
     path: &Path,
     mtime: FileTime,
-) -> Result<(), anyhow::Error> {
-    log::debug!("have high-res timesamp for {path:?}: {mtime}");
+    sha256: Option<&str>,
+) -> Result<Option<FileTime>> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("checking {path:?}")),
+    };
+    let mtime_from_disk = FileTime::from_last_modification_time(&metadata);
+    if mtime != mtime_from_disk {
+        let sha256_from_disk = if metadata.is_file() {
+            Some(sha256_file(path)?)
+        } else {
+            None
+        };
+        if sha256.is_some() && sha256_from_disk.as_deref() == sha256 {
+            if mtime_from_disk < mtime {
+                return Ok(Some(mtime_from_disk));
+            }
+        } else {
+            let contents = match sha256_from_disk {
+                Some(sha256) => format!("sha256 on disk = {sha256}"),
+                None => String::from("not a file"),
+            };
+            let layers = match repo.find_file(relative_path) {
+                Ok(found) => found
+                    .iter()
+                    .map(|found| {
+                        format!("\n  {} (mtime {})", found.description.digest, found.mtime)
+                    })
+                    .collect::<String>(),
+                Err(e) => format!(" (failed to look up which layers contain it: {e:#})"),
+            };
+            anyhow::bail!(
+                "{path:?} already exists with mtime {mtime_from_disk} instead of {mtime} \
+                ({contents}). Layers that contain it:{layers}"
+            );
+        }
+    }
+    std::fs::remove_file(path).with_context(|| format!("removing {path:?}"))?;
+    Ok(None)
+}
 
-    match file_timestamps.entry(path.to_owned()) {
-        btree_map::Entry::Vacant(entry) => {
-            entry.insert(mtime);
-            Ok(())
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::repo::tests::test_repo;
+    use crate::util::scratch_dir::ScratchDir;
+
+    const RLIB: &str = "target/debug/deps/libdep.rlib";
+    const DEP_INFO: &str = "target/debug/deps/dep.d";
+
+    fn write(path: &Path, contents: &str, mtime: i64) -> Result<()> {
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, contents)?;
+        filetime::set_file_mtime(path, FileTime::from_unix_time(mtime, 0))?;
+        Ok(())
+    }
+
+    /// Archive a fake dependency's layer.
+    fn archive_dep(repo: &Repo) -> Result<Manifest> {
+        let dep = ScratchDir::new()?;
+        write(&dep.path().join(RLIB), "rlib", 1)?;
+        let src = dep.path().join("src/lib.rs");
+        write(&dep.path().join(DEP_INFO), src.to_str().unwrap(), 1)?;
+        for dir in ["target/debug/deps", "target/debug", "target"] {
+            filetime::set_file_mtime(dep.path().join(dir), FileTime::from_unix_time(1, 0))?;
         }
-        btree_map::Entry::Occupied(entry) => anyhow::bail!(
-            "duplicate entry for {:?} ({:?}) when trying to insert {:?}",
-            entry.key(),
-            entry.get(),
-            mtime,
-        ),
+        let (manifest, _) =
+            archive_target_dir(repo, dep.path(), &BTreeMap::new(), &dep.remap_to_stable())?;
+        assert_eq!(manifest.entries.len(), 5);
+        Ok(manifest)
+    }
+
+    /// Unpack a fake dependency's layer into a fresh scratch dir, like `build_tarball()` does.
+    fn unpack_dep(repo: &Repo) -> Result<(ScratchDir, BTreeMap<PathBuf, UnpackedEntry>)> {
+        let manifest = archive_dep(repo)?;
+        let build = ScratchDir::new()?;
+        let unpacked = materialise(
+            repo,
+            &manifest,
+            None,
+            &build.path().join("target"),
+            Some(&build.remap_from_stable()),
+        )?;
+        let src = build.path().join("src/lib.rs");
+        assert_eq!(
+            std::fs::read_to_string(build.path().join(DEP_INFO))?,
+            src.to_str().unwrap()
+        );
+        Ok((build, unpacked))
+    }
+
+    #[test]
+    fn layers_only_contain_what_the_build_changed() -> Result<()> {
+        let (_local_dir, _, repo) = test_repo();
+        let (build, unpacked) = unpack_dep(&repo)?;
+
+        // cargo touches dependencies' outputs without changing them, and adds its own.
+        filetime::set_file_mtime(build.path().join(RLIB), FileTime::from_unix_time(2, 0))?;
+        let own = Path::new("target/debug/deps/libown.rlib");
+        write(&build.path().join(own), "own", 3)?;
+
+        let (manifest, _) =
+            archive_target_dir(&repo, build.path(), &unpacked, &build.remap_to_stable())?;
+        // Adding a file modifies the directory that it is in.
+        assert_eq!(
+            manifest.entries.keys().collect::<Vec<_>>(),
+            [Path::new("target/debug/deps"), own]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn layers_can_be_unpacked_over_an_earlier_build() -> Result<()> {
+        let (_local_dir, _, repo) = test_repo();
+        let manifest = archive_dep(&repo)?;
+
+        // Like running `cargo quickbuild build` twice in a workspace: the second time, the
+        // target dir has the layer and the workspace's own outputs in it already.
+        let target_dir = TempDir::new("target")?;
+        materialise(&repo, &manifest, None, target_dir.path(), None)?;
+        let own = target_dir.path().join("debug/deps/libown.rlib");
+        write(&own, "own", 3)?;
+        let unpacked = materialise(&repo, &manifest, None, target_dir.path(), None)?;
+
+        assert_eq!(unpacked.len(), manifest.entries.len());
+        assert_eq!(
+            std::fs::read_to_string(target_dir.path().join("debug/deps/libdep.rlib"))?,
+            "rlib"
+        );
+        assert_eq!(std::fs::read_to_string(own)?, "own");
+        Ok(())
+    }
+
+    #[test]
+    fn changing_a_dependency_is_an_error() -> Result<()> {
+        let (_local_dir, _, repo) = test_repo();
+        let (build, unpacked) = unpack_dep(&repo)?;
+
+        write(&build.path().join(RLIB), "changed", 2)?;
+
+        let error = archive_target_dir(&repo, build.path(), &unpacked, &build.remap_to_stable())
+            .unwrap_err();
+        assert!(error.to_string().contains(RLIB), "{error}");
+        Ok(())
     }
 }
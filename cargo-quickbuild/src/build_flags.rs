@@ -0,0 +1,250 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use cargo::core::compiler::{CompileKind, RustcTargetData};
+use cargo::Config;
+
+/// Environment variables that are read by build scripts (via the `cc` crate) and therefore
+/// change what ends up in `target/`, so they are part of the digest. Target-specific variants
+/// like `CC_x86_64_unknown_linux_gnu` and `TARGET_CC` are also captured.
+///
+/// Some variables are deliberately left out, because they only say where things are on this
+/// machine, and capturing them would stop identical toolchains on different machines from
+/// sharing layers. Builds still see them, because they inherit our environment:
+///
+/// * `PKG_CONFIG_PATH`, and everything else that isn't listed here.
+/// * The directories of the tools in `TOOL_ENV_VARS`: only their file names are captured.
+/// * The resolved path of a configured linker: only the value in the config is captured.
+const CAPTURED_ENV_VARS: &[&str] = &[
+    "CC", "CXX", "AR", "CFLAGS", "CXXFLAGS", "ARFLAGS", "LDFLAGS",
+];
+
+/// The captured variables that name a program, e.g. `CC=/usr/bin/clang`.
+const TOOL_ENV_VARS: &[&str] = &["CC", "CXX", "AR"];
+
+/// The compiler flags, cargo config and environment that affect a build, as seen from the
+/// user's project (`RUSTFLAGS`, `[build] rustflags`, `[target.*.linker]`, ...).
+///
+/// Our scratch projects live outside the user's project, so they wouldn't pick up its
+/// `.cargo/config.toml` by themselves.
+#[derive(Clone, Debug)]
+pub struct BuildFlags {
+    target: String,
+    /// The target triple (or target spec file) to cross-compile for, or `None` when building for
+    /// the host.
+    build_target: Option<String>,
+    rustflags: Vec<String>,
+    /// `[target.*.linker]` as it is written in the config, which is part of the digest...
+    linker: Option<String>,
+    /// ...and the program that it resolves to on this machine, which isn't.
+    resolved_linker: Option<PathBuf>,
+    env: BTreeMap<String, String>,
+}
+
+impl BuildFlags {
+    pub fn new(config: &Config, target_data: &RustcTargetData, kind: CompileKind) -> Self {
+        let linker = target_data.target_config(kind).linker.as_ref();
+        let env = std::env::vars()
+            .filter(|(key, _)| is_captured_env_var(key))
+            .map(|(key, value)| {
+                let value = if is_tool_env_var(&key) {
+                    file_name(&value)
+                } else {
+                    value
+                };
+                (key, value)
+            })
+            .collect();
+
+        Self {
+            target: target_data.short_name(&kind).to_string(),
+            build_target: match kind {
+                CompileKind::Host => None,
+                CompileKind::Target(target) => Some(target.rustc_target().to_string()),
+            },
+            rustflags: target_data.info(kind).rustflags.clone(),
+            linker: linker.map(|linker| linker.val.raw_value().to_owned()),
+            resolved_linker: linker.map(|linker| linker.val.resolve_program(config)),
+            env,
+        }
+    }
+
+    /// The `--target` to pass to cargo, if any. It is also written to `.cargo/config.toml`, but
+    /// `cargo clean` ignores `[build] target`.
+    pub fn target_args(&self) -> Vec<String> {
+        self.build_target
+            .iter()
+            .map(|target| format!("--target={target}"))
+            .collect()
+    }
+
+    pub fn rustflags(&self) -> &[String] {
+        &self.rustflags
+    }
+
+    /// Settings for the scratch build that only make sense on this machine, so they are passed
+    /// in the environment rather than in `cargo_config_toml()`, which is part of the digest.
+    /// Cargo prefers the environment to config files.
+    pub fn local_env(&self) -> Vec<(String, OsString)> {
+        self.resolved_linker
+            .iter()
+            .map(|linker| {
+                let target = self.target.to_uppercase().replace(['-', '.'], "_");
+                (
+                    format!("CARGO_TARGET_{target}_LINKER"),
+                    linker.clone().into_os_string(),
+                )
+            })
+            .collect()
+    }
+
+    /// The contents of `.cargo/config.toml` for a scratch project.
+    ///
+    /// The rustflags are written out for reference, but `CARGO_ENCODED_RUSTFLAGS` is what actually
+    /// gets used, because rustflags from different config files are merged rather than replaced.
+    ///
+    /// `[build] target` keys the layer by target: `--target=<host triple>` lays things out under
+    /// `target/<triple>/`, so it can't share layers with a plain native build.
+    pub fn cargo_config_toml(&self) -> String {
+        let mut config = format!("[build]\nrustflags = {:?}\n", self.rustflags);
+        if let Some(build_target) = &self.build_target {
+            config += &format!("target = {build_target:?}\n");
+        }
+        if let Some(linker) = &self.linker {
+            let target = &self.target;
+            config += &format!("\n[target.{target}]\nlinker = {linker:?}\n");
+        }
+        if !self.env.is_empty() {
+            config += "\n[env]\n";
+            for (key, value) in &self.env {
+                config += &format!("{key} = {value:?}\n");
+            }
+        }
+        config
+    }
+}
+
+fn is_captured_env_var(key: &str) -> bool {
+    base_env_var(key).is_some()
+}
+
+fn is_tool_env_var(key: &str) -> bool {
+    base_env_var(key).is_some_and(|var| TOOL_ENV_VARS.contains(&var))
+}
+
+/// Which of `CAPTURED_ENV_VARS` `key` is a variant of, if any.
+fn base_env_var(key: &str) -> Option<&'static str> {
+    let key = key
+        .strip_prefix("TARGET_")
+        .or_else(|| key.strip_prefix("HOST_"))
+        .unwrap_or(key);
+    CAPTURED_ENV_VARS.iter().copied().find(|var| {
+        key == *var
+            || key
+                .strip_prefix(var)
+                .is_some_and(|suffix| suffix.starts_with(['_', '-']))
+    })
+}
+
+/// `clang` for `/usr/bin/clang`. Tools can also be given with arguments (`CC="ccache gcc"`),
+/// which are kept.
+fn file_name(program: &str) -> String {
+    match program.split_once(' ') {
+        Some((program, args)) => format!("{} {args}", file_name(program)),
+        None => Path::new(program).file_name().map_or_else(
+            || program.to_owned(),
+            |name| name.to_string_lossy().into_owned(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn captures_compiler_env_vars_and_their_target_specific_variants() {
+        for key in [
+            "CC",
+            "CXXFLAGS",
+            "ARFLAGS",
+            "TARGET_CC",
+            "HOST_CFLAGS",
+            "CC_x86_64_unknown_linux_gnu",
+            "CC-aarch64-apple-darwin",
+        ] {
+            assert!(is_captured_env_var(key), "{key}");
+        }
+        for key in [
+            "CCACHE_DIR",
+            "PKG_CONFIG_PATH",
+            "ARCH",
+            "LD_LIBRARY_PATH",
+            "TARGET",
+        ] {
+            assert!(!is_captured_env_var(key), "{key}");
+        }
+        assert!(is_tool_env_var("TARGET_AR"));
+        assert!(!is_tool_env_var("ARFLAGS"));
+        assert_eq!(file_name("/usr/bin/clang"), "clang");
+        assert_eq!(
+            file_name("/usr/bin/ccache /usr/bin/gcc -m64"),
+            "ccache /usr/bin/gcc -m64"
+        );
+    }
+
+    fn flags(build_target: Option<&str>) -> BuildFlags {
+        BuildFlags {
+            target: build_target
+                .unwrap_or("x86_64-unknown-linux-gnu")
+                .to_owned(),
+            build_target: build_target.map(str::to_owned),
+            rustflags: vec![String::from("-Ctarget-cpu=native")],
+            linker: Some(String::from("tools/ld")),
+            resolved_linker: Some(PathBuf::from("/home/me/project/tools/ld")),
+            env: [(String::from("CC"), String::from("clang"))].into(),
+        }
+    }
+
+    #[test]
+    fn config_toml_for_the_host() {
+        assert_eq!(
+            flags(None).cargo_config_toml(),
+            "[build]\n\
+            rustflags = [\"-Ctarget-cpu=native\"]\n\
+            \n\
+            [target.x86_64-unknown-linux-gnu]\n\
+            linker = \"tools/ld\"\n\
+            \n\
+            [env]\n\
+            CC = \"clang\"\n"
+        );
+        assert_eq!(flags(None).target_args(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn config_toml_for_another_target() {
+        let flags = flags(Some("aarch64-unknown-linux-musl"));
+        assert_eq!(
+            flags.cargo_config_toml(),
+            "[build]\n\
+            rustflags = [\"-Ctarget-cpu=native\"]\n\
+            target = \"aarch64-unknown-linux-musl\"\n\
+            \n\
+            [target.aarch64-unknown-linux-musl]\n\
+            linker = \"tools/ld\"\n\
+            \n\
+            [env]\n\
+            CC = \"clang\"\n"
+        );
+        assert_eq!(flags.target_args(), ["--target=aarch64-unknown-linux-musl"]);
+        assert_eq!(
+            flags.local_env(),
+            [(
+                String::from("CARGO_TARGET_AARCH64_UNKNOWN_LINUX_MUSL_LINKER"),
+                OsString::from("/home/me/project/tools/ld")
+            )]
+        );
+    }
+}
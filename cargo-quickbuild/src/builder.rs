@@ -2,15 +2,15 @@ use std::collections::BTreeMap;
 use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
+use std::process::Command;
 
 use anyhow::Context;
 use anyhow::Result;
 use cargo::core::PackageId;
-use filetime::FileTime;
-use tar::Archive;
 
-use crate::archive::tar_target_dir;
-use crate::archive::tracked_unpack;
+use crate::archive::archive_target_dir;
+use crate::archive::materialise;
+use crate::archive::UnpackedEntry;
 use crate::description::PackageDescription;
 use crate::quick_resolve::BuildFor;
 use crate::quick_resolve::QuickResolve;
@@ -18,16 +18,19 @@ use crate::repo::Repo;
 use crate::stats::Stats;
 use crate::util::command::command;
 use crate::util::command::CommandExt;
-use crate::util::fixed_tempdir::FixedTempDir as TempDir;
+use crate::util::scratch_dir::{PathRemap, ScratchDir};
 
-pub fn build_tarball<'cfg, 'a>(
-    resolve: &QuickResolve<'cfg, 'a>,
+/// Build a single layer in a fresh scratch directory. `deps` must all be in the repo already.
+///
+/// This doesn't take a `QuickResolve`, because cargo's data structures can't be shared between
+/// threads, and the scheduler runs many of these at once.
+pub fn build_tarball(
     repo: &Repo,
-    package_id: PackageId,
-    build_for: BuildFor,
+    description: &PackageDescription,
+    deps: &[PackageDescription],
+    jobserver: &jobserver::Client,
 ) -> Result<()> {
-    let tempdir = TempDir::new("cargo-quickbuild-scratchpad")?;
-    assert!(tempdir.path().ends_with("cargo-quickbuild-scratchpad"));
+    let tempdir = ScratchDir::new()?;
     let scratch_dir = tempdir.path().join("cargo-quickbuild-scratchpad");
 
     // FIXME: this stats tracking is making it awkward to refactor this method into multiple bits.
@@ -35,35 +38,43 @@ pub fn build_tarball<'cfg, 'a>(
     let mut stats = Stats::new();
 
     // FIXME: do this by hand or something?
-    cargo_init(&scratch_dir)?;
+    cargo_init(&scratch_dir, description)?;
     stats.init_done();
 
-    let file_timestamps =
-        unpack_tarballs_of_deps(resolve, repo, package_id, build_for, &scratch_dir)?;
+    let unpacked = unpack_tarballs(
+        repo,
+        deps,
+        &scratch_dir.join("target"),
+        Some(&tempdir.remap_from_stable()),
+    )?;
     stats.untar_done();
 
-    let description = PackageDescription::new(resolve, package_id, build_for);
-    overwrite_manifest(&scratch_dir, &description)?;
+    overwrite_manifest(&scratch_dir, description)?;
 
     run_cargo_build(
         &scratch_dir,
-        repo.write_stdout(&description)?,
-        repo.write_stderr(&description)?,
+        description,
+        jobserver,
+        repo.write_stdout(description)?,
+        repo.write_stderr(description)?,
     )?;
     stats.build_done();
 
-    let description = PackageDescription::new(resolve, package_id, build_for);
-    let file = repo.write(&description)?;
-    tar_target_dir(scratch_dir, file, &file_timestamps)?;
+    let (manifest, stored_size) =
+        archive_target_dir(repo, &scratch_dir, &unpacked, &tempdir.remap_to_stable())?;
     stats.tar_done();
 
-    repo.commit(&description, stats)?;
+    repo.commit(description, deps, &manifest, stats, stored_size)?;
 
     Ok(())
 }
 
-pub fn cargo_init(scratch_dir: &std::path::PathBuf) -> Result<()> {
-    command(["cargo", "init", "--vcs=none"])
+pub fn cargo_init(
+    scratch_dir: &std::path::PathBuf,
+    description: &PackageDescription,
+) -> Result<()> {
+    cargo_command(description)
+        .args(["init", "--vcs=none"])
         .arg(scratch_dir)
         .try_execute()?;
 
@@ -76,32 +87,61 @@ pub fn unpack_tarballs_of_deps<'cfg, 'a>(
     package_id: PackageId,
     build_for: BuildFor,
     scratch_dir: &Path,
-) -> Result<BTreeMap<PathBuf, FileTime>> {
-    let mut file_timestamps = BTreeMap::default();
-    for (dep, build_for) in resolve
-        .recursive_deps_including_self(package_id, build_for)
+) -> Result<BTreeMap<PathBuf, UnpackedEntry>> {
+    let deps = layers_of_deps(resolve, package_id, build_for);
+    unpack_tarballs(repo, &deps, &scratch_dir.join("target"), None)
+        .with_context(|| format!("unpacking dependencies of {package_id:?}"))
+}
+
+/// The layers that need to be unpacked in order to build `package_id` (including its tests, if
+/// the build needs its dev-dependencies). Other workspace members that it depends on don't have
+/// layers, but their dependencies do.
+pub fn layers_of_deps<'cfg, 'a>(
+    resolve: &QuickResolve<'cfg, 'a>,
+    package_id: PackageId,
+    build_for: BuildFor,
+) -> Vec<PackageDescription> {
+    resolve
+        .recursive_deps_including_dev_deps(package_id, build_for)
         .into_iter()
-        .filter(|(id, _)| id != &package_id)
-    {
-        let description = PackageDescription::new(resolve, dep, build_for);
-        log::info!("unpacking tarball for {}", description.pretty_digest());
-        let file = repo
-            .read(&description)
-            .with_context(|| format!("reading description {description:?} for {package_id:?}"))?;
-        let mut archive = Archive::new(file);
+        .filter(|(id, _)| id != &package_id && !resolve.is_workspace_member(*id))
+        .map(|(dep, build_for)| PackageDescription::new(resolve, dep, build_for))
+        .collect()
+}
+
+pub fn unpack_tarballs(
+    repo: &Repo,
+    descriptions: &[PackageDescription],
+    target_dir: &Path,
+    remap: Option<&PathRemap>,
+) -> Result<BTreeMap<PathBuf, UnpackedEntry>> {
+    let mut unpacked = BTreeMap::default();
+    for description in descriptions {
+        log::info!("unpacking layer {}", description.pretty_digest());
+        let manifest = repo
+            .read(description)
+            .with_context(|| format!("reading description {description:?}"))?;
         // These should be *guaranteed* to already be built.
-        let mut timestamps = tracked_unpack(&mut archive, scratch_dir)
+        let extracted = repo.extract(description, &manifest)?;
+        let mut entries = materialise(repo, &manifest, extracted.as_deref(), target_dir, remap)
             .with_context(|| format!("unpacking {description:?}"))?;
-        file_timestamps.append(&mut timestamps);
+        unpacked.append(&mut entries);
     }
 
-    Ok(file_timestamps)
+    Ok(unpacked)
 }
 
 fn overwrite_manifest(
     scratch_dir: &Path,
     description: &PackageDescription,
 ) -> Result<(), anyhow::Error> {
+    let cargo_config_dir = scratch_dir.join(".cargo");
+    std::fs::create_dir_all(&cargo_config_dir)?;
+    std::fs::write(
+        cargo_config_dir.join("config.toml"),
+        description.cargo_config_toml(),
+    )?;
+
     let cargo_toml_path = scratch_dir.join("Cargo.toml");
     let mut cargo_toml = std::fs::OpenOptions::new()
         .write(true)
@@ -110,27 +150,60 @@ fn overwrite_manifest(
     write!(cargo_toml, "{}", description.cargo_toml_deps())?;
     cargo_toml.flush()?;
     drop(cargo_toml);
+
+    if description.has_build_script() {
+        std::fs::write(scratch_dir.join("build.rs"), "fn main() {}\n")?;
+    }
     Ok(())
 }
 
 pub fn run_cargo_build(
     scratch_dir: &std::path::PathBuf,
+    description: &PackageDescription,
+    jobserver: &jobserver::Client,
     stdout: impl Write + Send,
     stderr: impl Write + Send,
 ) -> Result<()> {
-    command(["cargo", "build", "--jobs=1"])
+    let mut cargo_build = cargo_command(description);
+    // Cargo picks up the jobserver from CARGO_MAKEFLAGS, so the number of rustc processes is
+    // limited across all of the layers that are being built at once.
+    jobserver.configure(&mut cargo_build);
+    cargo_build
+        .arg(description.mode().cargo_subcommand())
+        .arg(format!("--profile={}", description.profile_name()))
+        .args(description.flags().target_args())
         .current_dir(scratch_dir)
         .try_execute_tee(stdout, stderr)?;
 
-    command([
-        "cargo",
-        "clean",
-        "--offline",
-        "--package",
-        "cargo-quickbuild-scratchpad",
-    ])
-    .current_dir(scratch_dir)
-    .try_execute()?;
+    cargo_command(description)
+        .args([
+            "clean",
+            "--offline",
+            "--package",
+            "cargo-quickbuild-scratchpad",
+        ])
+        .arg(format!("--profile={}", description.profile_name()))
+        .args(description.flags().target_args())
+        .current_dir(scratch_dir)
+        .try_execute()?;
 
     Ok(())
 }
+
+/// Run cargo (and rustc) from the toolchain that the layer is described with, with the same
+/// rustflags as the user's project.
+fn cargo_command(description: &PackageDescription) -> Command {
+    let toolchain = description.toolchain();
+    let mut cargo = command([toolchain.cargo()]);
+    cargo
+        .env("RUSTC", toolchain.rustc())
+        // Layers are always built in (and archived from) the scratch dir's own target dir.
+        .env("CARGO_TARGET_DIR", "target")
+        .envs(description.flags().local_env())
+        .env_remove("RUSTFLAGS")
+        .env(
+            "CARGO_ENCODED_RUSTFLAGS",
+            description.flags().rustflags().join("\x1f"),
+        );
+    cargo
+}
@@ -1,95 +1,51 @@
 // I am allergic to files named build.rs that aren't build scripts. They bring me out in a rash.
 
-use std::fs::File;
-use std::path::{Path, PathBuf};
-
-use cargo::core::compiler::{CompileMode, UnitInterner};
-use cargo::core::resolver::features::FeaturesFor;
-use cargo::core::Workspace;
-use cargo::ops::CompileOptions;
-use cargo::Config;
-
-use crate::builder::unpack_tarballs_of_deps;
-use crate::quick_resolve::{create_quick_resolve, BuildFor};
-use crate::repo::Repo;
-use crate::resolve::create_resolve;
-use crate::scheduler::build_missing_packages;
-use crate::util::command::{command, CommandExt};
-
-// At some point I will pick a command-line parsing crate, but for now this will do.
-pub fn exec(args: &[String]) -> anyhow::Result<()> {
-    assert_eq!(args[0], "build");
-    assert_eq!(
-        &args[1..],
-        &args[0..0],
-        "unexpected argument to `cargo quick`"
-    );
-
-    let config = Config::default()?;
-
-    let ws = Workspace::new(&Path::new("Cargo.toml").canonicalize()?, &config)?;
-    let options = CompileOptions::new(&config, CompileMode::Build)?;
-
-    let interner = UnitInterner::new();
-    let workspace_resolve = create_resolve(&ws, &options, &interner)?;
-    let resolve = create_quick_resolve(&ws, &options, &workspace_resolve)?;
-
-    // FIXME: there has to be a better way to ask cargo for the list of root packages.
-    let pkg = *resolve
-        .workspace_resolve
-        .targeted_resolve
-        .sort()
-        .last()
-        .unwrap();
-    let root_package = *resolve
-        .workspace_resolve
-        .targeted_resolve
-        .path_to_top(&pkg)
-        .last()
-        .unwrap()
-        .0;
-
-    let repo = Repo::from_env();
-
-    build_missing_packages(&resolve, &repo, root_package)?;
-    let here = PathBuf::from(".");
-    let repo_root = here.clone();
-
-    assert!(
-        !repo_root.join("target").exists(),
-        "please remove your target dir before continuing"
-    );
+use cargo::core::compiler::CompileMode;
+use cargo::util::command_prelude::{
+    subcommand, App, AppExt, ArgMatches, ArgMatchesExt, ProfileChecking,
+};
+
+use crate::commands::{current_workspace, forwarded_args, new_config, prebuild_and_run};
+
+/// The subset of `cargo build`'s flags that we understand. Everything that is accepted here is
+/// also forwarded verbatim to the final `cargo build` invocation.
+pub fn cli() -> App {
+    subcommand("build")
+        .about("Compile a local package and all of its dependencies, using prebuilt layers")
+        .arg_package_spec(
+            "Package to build (see `cargo help pkgid`)",
+            "Build all packages in the workspace",
+            "Exclude packages from the build",
+        )
+        .arg_jobs()
+        .arg_targets_all(
+            "Build only this package's library",
+            "Build only the specified binary",
+            "Build all binaries",
+            "Build only the specified example",
+            "Build all examples",
+            "Build only the specified test target",
+            "Build all tests",
+            "Build only the specified bench target",
+            "Build all benches",
+            "Build all targets",
+        )
+        .arg_release("Build artifacts in release mode, with optimizations")
+        .arg_profile("Build artifacts with the specified profile")
+        .arg_features()
+        .arg_target_triple("Build for the target triple")
+        .arg_message_format()
+}
 
-    unpack_tarballs_of_deps(
-        &resolve,
-        &repo,
-        root_package,
-        // FIXME: assert that we've not been asked to build a proc-macro crate.
-        BuildFor(FeaturesFor::NormalOrDev),
-        &repo_root,
+pub fn exec(args: &ArgMatches) -> anyhow::Result<()> {
+    let config = new_config(args)?;
+    let ws = current_workspace(&config)?;
+    let options = args.compile_options(
+        &config,
+        CompileMode::Build,
+        Some(&ws),
+        ProfileChecking::Custom,
     )?;
 
-    let stdout_file = File::options()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(
-            home::home_dir()
-                .unwrap()
-                .join("tmp/quick/cargo-build.stdout"),
-        )?;
-    let stderr_file = File::options()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(
-            home::home_dir()
-                .unwrap()
-                .join("tmp/quick/cargo-build.stderr"),
-        )?;
-    command(["cargo", "build", "--jobs=1"])
-        .current_dir(&here)
-        .try_execute_tee(stdout_file, stderr_file)?;
-
-    Ok(())
+    prebuild_and_run(&ws, &options, "build", forwarded_args(&cli(), args))
 }
@@ -0,0 +1,51 @@
+use cargo::core::compiler::CompileMode;
+use cargo::util::command_prelude::{
+    subcommand, App, AppExt, ArgMatches, ArgMatchesExt, ProfileChecking,
+};
+
+use crate::commands::{current_workspace, forwarded_args, new_config, prebuild_and_run};
+
+/// The subset of `cargo check`'s flags that we understand. Everything that is accepted here is
+/// also forwarded verbatim to the final `cargo check` invocation.
+pub fn cli() -> App {
+    subcommand("check")
+        .about("Check a local package for errors, using prebuilt metadata for its dependencies")
+        .arg_package_spec(
+            "Package to check",
+            "Check all packages in the workspace",
+            "Exclude packages from the check",
+        )
+        .arg_jobs()
+        .arg_targets_all(
+            "Check only this package's library",
+            "Check only the specified binary",
+            "Check all binaries",
+            "Check only the specified example",
+            "Check all examples",
+            "Check only the specified test target",
+            "Check all tests",
+            "Check only the specified bench target",
+            "Check all benches",
+            "Check all targets",
+        )
+        .arg_release("Check artifacts in release mode, with optimizations")
+        .arg_profile("Check artifacts with the specified profile")
+        .arg_features()
+        .arg_target_triple("Check for the target triple")
+        .arg_message_format()
+}
+
+pub fn exec(args: &ArgMatches) -> anyhow::Result<()> {
+    let config = new_config(args)?;
+    let ws = current_workspace(&config)?;
+    // Like cargo, `--profile=test` means checking the tests too.
+    let test = matches!(args.value_of("profile"), Some("test"));
+    let options = args.compile_options(
+        &config,
+        CompileMode::Check { test },
+        Some(&ws),
+        ProfileChecking::LegacyTestOnly,
+    )?;
+
+    prebuild_and_run(&ws, &options, "check", forwarded_args(&cli(), args))
+}
@@ -0,0 +1,66 @@
+use cargo::core::compiler::CompileMode;
+use cargo::util::command_prelude::{
+    subcommand, App, AppExt, Arg, ArgMatches, ArgMatchesExt, ProfileChecking,
+};
+
+use crate::commands::{current_workspace, forwarded_args, new_config, prebuild_and_run};
+
+/// The subset of `cargo clippy`'s flags that we understand. Everything that is accepted here is
+/// also forwarded verbatim to the final `cargo clippy` invocation, along with any lint flags
+/// after `--`.
+///
+/// Clippy only lints workspace members, and checks dependencies with plain rustc, so it can use
+/// the same layers as `check`.
+pub fn cli() -> App {
+    subcommand("clippy")
+        .trailing_var_arg(true)
+        .about("Lint a local package with clippy, using prebuilt metadata for its dependencies")
+        .arg(
+            Arg::new("args")
+                .help("Flags for clippy (e.g. -D warnings)")
+                .multiple_values(true)
+                .last(true),
+        )
+        .arg_package_spec(
+            "Package to lint",
+            "Lint all packages in the workspace",
+            "Exclude packages from linting",
+        )
+        .arg_jobs()
+        .arg_targets_all(
+            "Lint only this package's library",
+            "Lint only the specified binary",
+            "Lint all binaries",
+            "Lint only the specified example",
+            "Lint all examples",
+            "Lint only the specified test target",
+            "Lint all tests",
+            "Lint only the specified bench target",
+            "Lint all benches",
+            "Lint all targets",
+        )
+        .arg_release("Lint artifacts in release mode, with optimizations")
+        .arg_profile("Lint artifacts with the specified profile")
+        .arg_features()
+        .arg_target_triple("Lint for the target triple")
+        .arg_message_format()
+}
+
+pub fn exec(args: &ArgMatches) -> anyhow::Result<()> {
+    let config = new_config(args)?;
+    let ws = current_workspace(&config)?;
+    let test = matches!(args.value_of("profile"), Some("test"));
+    let options = args.compile_options(
+        &config,
+        CompileMode::Check { test },
+        Some(&ws),
+        ProfileChecking::LegacyTestOnly,
+    )?;
+
+    let mut cargo_args = forwarded_args(&cli(), args);
+    if let Some(clippy_args) = args.values_of("args") {
+        cargo_args.push(String::from("--"));
+        cargo_args.extend(clippy_args.map(String::from));
+    }
+    prebuild_and_run(&ws, &options, "clippy", cargo_args)
+}
@@ -0,0 +1,51 @@
+use cargo::core::compiler::CompileMode;
+use cargo::util::command_prelude::{
+    opt, subcommand, App, AppExt, ArgMatches, ArgMatchesExt, ProfileChecking,
+};
+
+use crate::commands::{current_workspace, forwarded_args, new_config, prebuild_and_run};
+
+/// The subset of `cargo doc`'s flags that we understand. Everything that is accepted here is
+/// also forwarded verbatim to the final `cargo doc` invocation.
+///
+/// Layers only hold the metadata that rustdoc needs for each dependency, not their
+/// documentation, so `cargo doc` still runs rustdoc on dependencies unless `--no-deps` is given.
+pub fn cli() -> App {
+    subcommand("doc")
+        .about("Build a package's documentation, using prebuilt metadata for its dependencies")
+        .arg(opt(
+            "open",
+            "Opens the docs in a browser after the operation",
+        ))
+        .arg_package_spec(
+            "Package to document",
+            "Document all packages in the workspace",
+            "Exclude packages from the build",
+        )
+        .arg(opt("no-deps", "Don't build documentation for dependencies"))
+        .arg(opt("document-private-items", "Document private items"))
+        .arg_jobs()
+        .arg_targets_lib_bin_example(
+            "Document only this package's library",
+            "Document only the specified binary",
+            "Document all binaries",
+            "Document only the specified example",
+            "Document all examples",
+        )
+        .arg_release("Build artifacts in release mode, with optimizations")
+        .arg_profile("Build artifacts with the specified profile")
+        .arg_features()
+        .arg_target_triple("Build for the target triple")
+        .arg_message_format()
+}
+
+pub fn exec(args: &ArgMatches) -> anyhow::Result<()> {
+    let config = new_config(args)?;
+    let ws = current_workspace(&config)?;
+    let mode = CompileMode::Doc {
+        deps: !args.is_present("no-deps"),
+    };
+    let options = args.compile_options(&config, mode, Some(&ws), ProfileChecking::Custom)?;
+
+    prebuild_and_run(&ws, &options, "doc", forwarded_args(&cli(), args))
+}
@@ -1,34 +1,37 @@
 use std::collections::HashSet;
 
 use anyhow::bail;
-use cargo::core::compiler::{CompileMode, UnitInterner};
+use cargo::core::compiler::{CompileKind, CompileMode, UnitInterner};
 use cargo::core::resolver::features::FeaturesFor;
 use cargo::core::{Dependency, Package, PackageId, Source, SourceId, Workspace};
 use cargo::ops::CompileOptions;
 use cargo::sources::SourceConfigMap;
+use cargo::util::command_prelude::{subcommand, App, Arg, ArgMatches};
+use cargo::util::interning::InternedString;
 use cargo::util::Filesystem;
 use cargo::{CargoResult, Config};
 
 use crate::builder::unpack_tarballs_of_deps;
+use crate::commands::cargo;
 use crate::quick_resolve::{create_quick_resolve, BuildFor};
 use crate::repo::Repo;
 use crate::resolve::create_resolve;
 use crate::scheduler::build_missing_packages;
 use crate::util::command::{command, CommandExt};
-use crate::util::fixed_tempdir::FixedTempDir as TempDir;
+use crate::util::scratch_dir::ScratchDir;
 
-// At some point I will pick a command-line parsing crate, but for now this will do.
-pub fn exec(args: &[String]) -> anyhow::Result<()> {
-    assert_eq!(args[0], "install");
-    if args.len() != 2 {
-        bail!("USAGE: cargo quickbuild install $package_name");
-    }
-    let krate = args[1].as_str();
-    assert_eq!(args, &["install", krate]);
+pub fn cli() -> App {
+    subcommand("install")
+        .about("Install a crate from crates.io, using prebuilt layers for its dependencies")
+        .arg(Arg::new("crate").value_name("CRATE").required(true))
+}
+
+pub fn exec(args: &ArgMatches) -> anyhow::Result<()> {
+    let krate = args.value_of("crate").unwrap();
 
     let mut config = Config::default()?;
     config.reload_rooted_at(home::cargo_home()?)?;
-    let tempdir = TempDir::new("cargo-quickbuild-install-scratchpad")?;
+    let tempdir = ScratchDir::new()?;
     config.configure(
         0,
         false,
@@ -57,14 +60,21 @@ pub fn exec(args: &[String]) -> anyhow::Result<()> {
 
         let mut ws = Workspace::ephemeral(package.clone(), &config, Some(target_dir), false)?;
         ws.set_ignore_lock(true);
-        let options = CompileOptions::new(&config, CompileMode::Build)?;
+        let mut options = CompileOptions::new(&config, CompileMode::Build)?;
+        // Match the profile that `cargo install` will use.
+        options.build_config.requested_profile = InternedString::new("release");
 
         let interner = UnitInterner::new();
         let workspace_resolve = create_resolve(&ws, &options, &interner)?;
-        let resolve = create_quick_resolve(&ws, &options, &workspace_resolve)?;
+        let resolve = create_quick_resolve(&ws, &options, &workspace_resolve, CompileKind::Host)?;
 
-        let repo = Repo::from_env();
-        build_missing_packages(&resolve, &repo, package.package_id())?;
+        let repo = Repo::from_env()?;
+        build_missing_packages(
+            &resolve,
+            &repo,
+            &[package.package_id()],
+            options.build_config.jobs,
+        )?;
 
         unpack_tarballs_of_deps(
             &resolve,
@@ -75,19 +85,11 @@ pub fn exec(args: &[String]) -> anyhow::Result<()> {
         )?;
     }
 
-    // FIXME: this doesn't end up building with the same profile as `run_cargo_build()`, so it
-    // duplicates all of the work.
-    command([
-        "cargo",
-        "install",
-        "--offline",
-        "--debug",
-        "--force",
-        "--target-dir",
-        tempdir.path().join("target").to_str().unwrap(),
-        krate,
-    ])
-    .try_execute()?;
+    command([cargo()])
+        .args(["install", "--offline", "--force", "--target-dir"])
+        .arg(tempdir.path().join("target"))
+        .arg(krate)
+        .try_execute()?;
 
     Ok(())
 }
@@ -122,7 +124,7 @@ where
             let is_yanked: bool = if dep.version_req().is_exact() {
                 let version: String = dep.version_req().to_string();
                 PackageId::new(dep.package_name(), &version[1..], source.source_id())
-                    .map_or(false, |pkg_id| source.is_yanked(pkg_id).unwrap_or(false))
+                    .is_ok_and(|pkg_id| source.is_yanked(pkg_id).unwrap_or(false))
             } else {
                 false
             };
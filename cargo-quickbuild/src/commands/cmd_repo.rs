@@ -1,38 +1,353 @@
-use std::{fs::File, path::PathBuf};
+use std::path::PathBuf;
 
-use anyhow::bail;
-use tar::Archive;
+use cargo::util::command_prelude::{multi_opt, opt, subcommand, App, Arg, ArgMatches};
 
-use crate::{archive::get_high_res_mtime, repo::Repo};
+use crate::description::StoredDescription;
+use crate::manifest::EntryKind;
+use crate::repo::gc::GcPolicy;
+use crate::repo::Repo;
+use crate::util::units::{format_duration, format_size, parse_duration, parse_size};
 
-// At some point I will pick a command-line parsing crate, but for now this will do.
-pub fn exec(args: &[String]) -> anyhow::Result<()> {
-    assert_eq!(args[0], "repo");
-    assert_eq!(args[1], "find");
-    if args.len() != 3 {
-        bail!("USAGE: cargo quickbuild repo find $filename");
+pub fn cli() -> App {
+    subcommand("repo")
+        .about("Inspect the repository of prebuilt layers")
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(
+            subcommand("list")
+                .about("List the layers in the local repository (without fetching any from the remote)")
+                .arg(opt("name", "Only list layers of this crate").value_name("NAME"))
+                .arg(opt("version", "Only list layers of this crate version").value_name("VERSION"))
+                .arg(
+                    opt("build-for", "Only list layers built for the target or the host")
+                        .value_name("BUILD_FOR")
+                        .possible_values(["target", "host"]),
+                )
+                .arg(
+                    opt("older-than", "Only list layers built longer ago than this (e.g. 30d)")
+                        .value_name("AGE"),
+                )
+                .arg(
+                    opt("newer-than", "Only list layers built more recently than this (e.g. 12h)")
+                        .value_name("AGE"),
+                )
+                .arg(
+                    opt("larger-than", "Only list layers with more than this many bytes of files (e.g. 10M)")
+                        .value_name("SIZE"),
+                )
+                .arg(
+                    opt("smaller-than", "Only list layers with fewer than this many bytes of files")
+                        .value_name("SIZE"),
+                ),
+        )
+        .subcommand(
+            subcommand("show")
+                .about("Show everything that is known about a layer")
+                .arg(
+                    Arg::new("layer")
+                        .value_name("DIGEST_OR_CRATE")
+                        .help("The layer's digest, or a crate name (optionally NAME@VERSION) to show all of its layers")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            subcommand("find")
+                .about("List the layers that contain a file")
+                .arg(
+                    Arg::new("filename")
+                        .value_name("FILENAME")
+                        .help("Path of the file, relative to the project root (e.g. target/debug/...)")
+                        .required_unless_present("rebuild-index"),
+                )
+                .arg(opt(
+                    "rebuild-index",
+                    "Rebuild the index of which layers contain which files from scratch, picking up \
+                    layers that were added to the repo by other machines",
+                )),
+        )
+        .subcommand(
+            subcommand("verify")
+                .about("Check that every layer in the repository is complete and uncorrupted")
+                .arg(opt(
+                    "quarantine",
+                    "Move broken layers out of the way so that they get rebuilt, and remove abandoned files",
+                )),
+        )
+        .subcommand(
+            subcommand("gc")
+                .about("Evict layers from the local repository, least recently used first")
+                .after_help(
+                    "This needs the repository to itself, so it fails if any other \
+                    cargo-quickbuild processes (e.g. builds) are using it.",
+                )
+                .arg(
+                    opt("max-size", "Evict layers until the repository is no bigger than this (e.g. 10G)")
+                        .value_name("SIZE"),
+                )
+                .arg(
+                    opt("max-age", "Evict layers that haven't been used for this long (e.g. 30d)")
+                        .value_name("AGE"),
+                )
+                .arg(multi_opt(
+                    "lockfile",
+                    "PATH",
+                    "Evict layers that none of these Cargo.lock files could use",
+                ))
+                .arg(opt("dry-run", "Say what would be evicted, without evicting anything")),
+        )
+}
+
+pub fn exec(args: &ArgMatches) -> anyhow::Result<()> {
+    match args.subcommand() {
+        Some(("list", args)) => exec_list(args),
+        Some(("show", args)) => exec_show(args),
+        Some(("find", args)) => exec_find(args),
+        Some(("verify", args)) => exec_verify(args),
+        Some(("gc", args)) => exec_gc(args),
+        _ => unreachable!("clap only accepts known subcommands"),
+    }
+}
+
+fn exec_list(args: &ArgMatches) -> anyhow::Result<()> {
+    let name = args.value_of("name");
+    let version = args.value_of("version");
+    let build_for = args.value_of("build-for");
+    let older_than = args
+        .value_of("older-than")
+        .map(parse_duration)
+        .transpose()?;
+    let newer_than = args
+        .value_of("newer-than")
+        .map(parse_duration)
+        .transpose()?;
+    let larger_than = args.value_of("larger-than").map(parse_size).transpose()?;
+    let smaller_than = args.value_of("smaller-than").map(parse_size).transpose()?;
+
+    // Going through the remote would download every layer's description and manifest.
+    let repo = Repo::local_from_env()?;
+    for description in repo.descriptions()? {
+        if name.is_some_and(|name| name != description.name)
+            || version.is_some_and(|version| version != description.version)
+            || build_for.is_some_and(|build_for| build_for != description.build_for)
+        {
+            continue;
+        }
+        let age = description.age()?;
+        if older_than.is_some_and(|older_than| age <= older_than)
+            || newer_than.is_some_and(|newer_than| age >= newer_than)
+        {
+            continue;
+        }
+        let size = match repo.manifest(&description.digest)? {
+            Some(manifest) => manifest.size(),
+            None => continue,
+        };
+        if larger_than.is_some_and(|larger_than| size <= larger_than)
+            || smaller_than.is_some_and(|smaller_than| size >= smaller_than)
+        {
+            continue;
+        }
+
+        println!(
+            "{:<40} {:>8} {:>5} ago  {}",
+            format!(
+                "{} {} ({})",
+                description.name, description.version, description.build_for
+            ),
+            format_size(size),
+            format_duration(age),
+            description.digest,
+        );
+    }
+
+    Ok(())
+}
+
+fn exec_show(args: &ArgMatches) -> anyhow::Result<()> {
+    let layer = args.value_of("layer").unwrap();
+
+    let repo = Repo::from_env()?;
+    let descriptions: Vec<StoredDescription> = match repo.description(layer)? {
+        Some(description) => vec![description],
+        None => repo
+            .descriptions()?
+            .into_iter()
+            .filter(|description| {
+                description.name == layer
+                    || format!("{}@{}", description.name, description.version) == layer
+            })
+            .collect(),
+    };
+    anyhow::ensure!(!descriptions.is_empty(), "no layers match {layer:?}");
+
+    for (i, description) in descriptions.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        show(&repo, description)?;
     }
 
-    let filename = args[2].as_str();
-    assert_eq!(args, &["repo", "find", filename]);
-    let filename = PathBuf::from(filename);
+    Ok(())
+}
 
-    let repo = Repo::from_env();
-    for tarball_path in repo.find_file(&filename) {
-        let mut archive = Archive::new(File::open(&tarball_path).unwrap());
-        let mut entry = archive
-            .entries()
-            .unwrap()
-            .map(|entry| entry.unwrap())
-            .find(|entry| entry.path().unwrap().as_ref() == filename)
-            .unwrap();
+fn show(repo: &Repo, description: &StoredDescription) -> anyhow::Result<()> {
+    let digest = &description.digest;
+    println!("layer:      {digest}");
+    println!(
+        "package:    {} {} ({})",
+        description.name, description.version, description.source
+    );
+    println!("build for:  {}", description.build_for);
+    println!("profile:    {}", description.profile);
+    println!("mode:       {}", description.mode.cargo_subcommand());
+    println!("features:   {}", description.features.join(", "));
+    println!("toolchain:  {}", description.toolchain.fingerprint());
+    println!(
+        "created:    {} ({} ago)",
+        description.created_at,
+        format_duration(description.age()?)
+    );
+    if let Some(stats) = repo.stats(digest)? {
+        println!(
+            "stats:      init {:.1}s, unpack {:.1}s, build {:.1}s, archive {:.1}s, \
+            {} of new blobs ({})",
+            stats.init_duration.as_secs_f64(),
+            stats.untar_duration.as_secs_f64(),
+            stats.build_duration.as_secs_f64(),
+            stats.tar_duration.as_secs_f64(),
+            format_size(stats.stored_size),
+            stats.compression,
+        );
+    }
+    for suffix in ["stdout", "stderr"] {
+        let path = repo.log_path(digest, suffix);
+        if path.exists() {
+            println!("{suffix}:     {}", path.display());
+        }
+    }
+
+    println!("\ndependencies:");
+    for dep in &description.deps {
+        println!("  {dep}");
+    }
 
-        let mtime = get_high_res_mtime(&mut entry)?;
+    println!("\nCargo.toml:");
+    for line in description.cargo_toml.lines() {
+        println!("  {line}");
+    }
+    println!("\n.cargo/config.toml:");
+    for line in description.cargo_config_toml.lines() {
+        println!("  {line}");
+    }
 
-        let mtime = chrono::NaiveDateTime::from_timestamp(mtime.seconds(), mtime.nanoseconds());
+    if let Some(manifest) = repo.manifest(digest)? {
+        println!(
+            "\nfiles ({} entries, {}):",
+            manifest.entries.len(),
+            format_size(manifest.size())
+        );
+        for (path, entry) in &manifest.entries {
+            let mtime = chrono::NaiveDateTime::from_timestamp(
+                entry.mtime.unix_seconds(),
+                entry.mtime.nanoseconds(),
+            );
+            let (kind, size) = match &entry.kind {
+                EntryKind::File { size, .. } => ("file", format_size(*size)),
+                EntryKind::Dir => ("dir", String::new()),
+                EntryKind::Symlink { .. } => ("symlink", String::new()),
+            };
+            println!(
+                "  {:o} {kind:<7} {size:>8} {mtime} {}",
+                entry.mode,
+                path.display()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn exec_find(args: &ArgMatches) -> anyhow::Result<()> {
+    let repo = Repo::from_env()?;
+    if args.is_present("rebuild-index") {
+        repo.rebuild_index()?;
+    }
+    let filename = match args.value_of("filename") {
+        Some(filename) => PathBuf::from(filename),
+        None => return Ok(()),
+    };
+
+    for found in repo.find_file(&filename)? {
+        let description = found.description;
+        let mtime =
+            chrono::NaiveDateTime::from_timestamp(found.mtime.seconds(), found.mtime.nanoseconds());
+        let size = match found.size {
+            Some(size) => format!(", size {}", format_size(size)),
+            None => String::new(),
+        };
+
+        println!(
+            "{filename:?} found in: {} {} ({}) {} with mtime {mtime}{size}",
+            description.name, description.version, description.build_for, description.digest
+        );
+    }
+
+    Ok(())
+}
+
+fn exec_verify(args: &ArgMatches) -> anyhow::Result<()> {
+    let quarantine = args.is_present("quarantine");
+
+    let repo = Repo::from_env()?;
+    let problems = repo.verify(quarantine)?;
+    for problem in &problems {
+        println!("{}: {}", problem.key, problem.description);
+    }
+
+    match (problems.len(), quarantine) {
+        (0, _) => println!("no problems found"),
+        (n, true) => println!("quarantined {n} problems"),
+        (n, false) => {
+            anyhow::bail!("found {n} problems (run with --quarantine to move them out of the way)")
+        }
+    }
+    Ok(())
+}
+
+fn exec_gc(args: &ArgMatches) -> anyhow::Result<()> {
+    let policy = GcPolicy {
+        max_size: args.value_of("max-size").map(parse_size).transpose()?,
+        max_age: args.value_of("max-age").map(parse_duration).transpose()?,
+        lockfiles: args
+            .values_of("lockfile")
+            .into_iter()
+            .flatten()
+            .map(PathBuf::from)
+            .collect(),
+    };
+    if policy.max_size.is_none() && policy.max_age.is_none() && policy.lockfiles.is_empty() {
+        anyhow::bail!("nothing to do (pass at least one of --max-size, --max-age or --lockfile)");
+    }
+    let dry_run = args.is_present("dry-run");
 
-        println!("{filename:?} found in: {tarball_path:?} with mtime {mtime}");
+    let repo = Repo::exclusive_local_from_env()?;
+    let report = repo.gc(&policy, dry_run)?;
+    for eviction in &report.evicted {
+        println!(
+            "{} {}: {}",
+            format_size(eviction.freed),
+            eviction.digest,
+            eviction.reason
+        );
     }
 
+    let verb = if dry_run { "would free" } else { "freed" };
+    println!(
+        "{} layers and {} blobs: {verb} {}, {} remaining",
+        report.evicted.len(),
+        report.blobs_removed,
+        format_size(report.freed),
+        format_size(report.remaining),
+    );
     Ok(())
 }
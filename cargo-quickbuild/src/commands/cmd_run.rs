@@ -0,0 +1,49 @@
+use cargo::core::compiler::CompileMode;
+use cargo::util::command_prelude::{
+    subcommand, App, AppExt, Arg, ArgMatches, ArgMatchesExt, ProfileChecking,
+};
+
+use crate::commands::{current_workspace, forwarded_args, new_config, prebuild_and_run};
+
+/// The subset of `cargo run`'s flags that we understand. Everything that is accepted here is
+/// also forwarded verbatim to the final `cargo run` invocation, along with the program's
+/// arguments.
+pub fn cli() -> App {
+    subcommand("run")
+        .trailing_var_arg(true)
+        .about("Run a binary or example of the local package, using prebuilt layers")
+        .arg(
+            Arg::new("args")
+                .help("Arguments for the binary")
+                .multiple_values(true),
+        )
+        .arg_targets_bin_example(
+            "Name of the bin target to run",
+            "Name of the example target to run",
+        )
+        .arg_package("Package with the target to run")
+        .arg_jobs()
+        .arg_release("Build artifacts in release mode, with optimizations")
+        .arg_profile("Build artifacts with the specified profile")
+        .arg_features()
+        .arg_target_triple("Build for the target triple")
+        .arg_message_format()
+}
+
+pub fn exec(args: &ArgMatches) -> anyhow::Result<()> {
+    let config = new_config(args)?;
+    let ws = current_workspace(&config)?;
+    let options = args.compile_options(
+        &config,
+        CompileMode::Build,
+        Some(&ws),
+        ProfileChecking::Custom,
+    )?;
+
+    let mut cargo_args = forwarded_args(&cli(), args);
+    if let Some(program_args) = args.values_of("args") {
+        cargo_args.push(String::from("--"));
+        cargo_args.extend(program_args.map(String::from));
+    }
+    prebuild_and_run(&ws, &options, "run", cargo_args)
+}
@@ -0,0 +1,73 @@
+use cargo::core::compiler::CompileMode;
+use cargo::util::command_prelude::{
+    opt, subcommand, App, AppExt, Arg, ArgMatches, ArgMatchesExt, ProfileChecking,
+};
+
+use crate::commands::{current_workspace, forwarded_args, new_config, prebuild_and_run};
+
+/// The subset of `cargo test`'s flags that we understand. Everything that is accepted here is
+/// also forwarded verbatim to the final `cargo test` invocation, along with the test filter and
+/// the test binary's arguments.
+pub fn cli() -> App {
+    subcommand("test")
+        .trailing_var_arg(true)
+        .about("Run the tests of a local package, using prebuilt layers for its dependencies")
+        .arg(
+            Arg::new("TESTNAME")
+                .help("If specified, only run tests containing this string in their names"),
+        )
+        .arg(
+            Arg::new("args")
+                .help("Arguments for the test binary")
+                .multiple_values(true)
+                .last(true),
+        )
+        .arg_targets_all(
+            "Test only this package's library unit tests",
+            "Test only the specified binary",
+            "Test all binaries",
+            "Test only the specified example",
+            "Test all examples",
+            "Test only the specified test target",
+            "Test all tests",
+            "Test only the specified bench target",
+            "Test all benches",
+            "Test all targets",
+        )
+        .arg(opt("doc", "Test only this library's documentation"))
+        .arg(opt("no-run", "Compile, but don't run tests"))
+        .arg(opt("no-fail-fast", "Run all tests regardless of failure"))
+        .arg_package_spec(
+            "Package to run tests for",
+            "Test all packages in the workspace",
+            "Exclude packages from the test",
+        )
+        .arg_jobs()
+        .arg_release("Build artifacts in release mode, with optimizations")
+        .arg_profile("Build artifacts with the specified profile")
+        .arg_features()
+        .arg_target_triple("Build for the target triple")
+        .arg_message_format()
+}
+
+pub fn exec(args: &ArgMatches) -> anyhow::Result<()> {
+    let config = new_config(args)?;
+    let ws = current_workspace(&config)?;
+    let mode = if args.is_present("doc") {
+        CompileMode::Doctest
+    } else {
+        CompileMode::Test
+    };
+    let mut options = args.compile_options(&config, mode, Some(&ws), ProfileChecking::Custom)?;
+    // Like cargo, tests are built with the `test` profile unless another one is requested.
+    options.build_config.requested_profile =
+        args.get_profile_name(&config, "test", ProfileChecking::Custom)?;
+
+    let mut cargo_args = forwarded_args(&cli(), args);
+    cargo_args.extend(args.value_of("TESTNAME").map(String::from));
+    if let Some(test_args) = args.values_of("args") {
+        cargo_args.push(String::from("--"));
+        cargo_args.extend(test_args.map(String::from));
+    }
+    prebuild_and_run(&ws, &options, "test", cargo_args)
+}
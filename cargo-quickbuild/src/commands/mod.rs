@@ -1,3 +1,171 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use cargo::core::compiler::UnitInterner;
+use cargo::core::resolver::features::FeaturesFor;
+use cargo::core::Workspace;
+use cargo::ops::CompileOptions;
+use cargo::util::command_prelude::{App, ArgMatches, ArgMatchesExt, Config};
+
+use crate::builder::{layers_of_deps, unpack_tarballs};
+use crate::quick_resolve::{create_quick_resolve, BuildFor};
+use crate::repo::Repo;
+use crate::resolve::create_resolve;
+use crate::scheduler::build_missing_packages;
+use crate::util::command::{command, CommandExt};
+
 pub mod cmd_build;
+pub mod cmd_check;
+pub mod cmd_clippy;
+pub mod cmd_doc;
 pub mod cmd_install;
 pub mod cmd_repo;
+pub mod cmd_run;
+pub mod cmd_test;
+
+pub fn builtin() -> Vec<App> {
+    vec![
+        cmd_build::cli(),
+        cmd_check::cli(),
+        cmd_clippy::cli(),
+        cmd_doc::cli(),
+        cmd_install::cli(),
+        cmd_repo::cli(),
+        cmd_run::cli(),
+        cmd_test::cli(),
+    ]
+}
+
+pub fn builtin_exec(cmd: &str) -> Option<fn(&ArgMatches) -> anyhow::Result<()>> {
+    let f = match cmd {
+        "build" => cmd_build::exec,
+        "check" => cmd_check::exec,
+        "clippy" => cmd_clippy::exec,
+        "doc" => cmd_doc::exec,
+        "install" => cmd_install::exec,
+        "repo" => cmd_repo::exec,
+        "run" => cmd_run::exec,
+        "test" => cmd_test::exec,
+        _ => return None,
+    };
+    Some(f)
+}
+
+/// Reconstruct the flags that clap parsed for `app`, so that they can be handed on to the real
+/// cargo command. Positional arguments are not forwarded.
+pub fn forwarded_args(app: &App, matches: &ArgMatches) -> Vec<String> {
+    let mut forwarded = Vec::new();
+    for arg in app.get_arguments() {
+        let (id, long) = match arg.get_long() {
+            // `--help` and `--version` are listed here but never make it into `matches`.
+            Some(long) if matches.is_valid_arg(arg.get_id()) => (arg.get_id(), long),
+            _ => continue,
+        };
+        let occurrences = matches.occurrences_of(id) as usize;
+        if occurrences == 0 {
+            continue;
+        }
+        if !arg.is_takes_value_set() {
+            forwarded.extend(std::iter::repeat_n(format!("--{long}"), occurrences));
+            continue;
+        }
+        let values: Vec<_> = matches.values_of(id).unwrap_or_default().collect();
+        forwarded.extend(values.iter().map(|value| format!("--{long}={value}")));
+        // Flags like `--bin` can also be given without a value (to list the options).
+        forwarded.extend(std::iter::repeat_n(
+            format!("--{long}"),
+            occurrences.saturating_sub(values.len()),
+        ));
+    }
+    forwarded
+}
+
+/// A `Config` that accepts the `--target` flags in `args`.
+///
+/// The version of cargo that we are built against still wants `-Zmultitarget` before it accepts
+/// more than one `--target`, but the cargo that we hand over to doesn't, so enable that one
+/// unstable flag (and only that one) when several are given.
+pub fn new_config(args: &ArgMatches) -> anyhow::Result<Config> {
+    let mut config = Config::default()?;
+    if args._values_of("target").len() > 1 {
+        let nightly_features_allowed = config.nightly_features_allowed;
+        config.nightly_features_allowed = true;
+        let result = config.configure(
+            0,
+            false,
+            None,
+            false,
+            false,
+            false,
+            &None,
+            &[String::from("multitarget")],
+            &[],
+        );
+        config.nightly_features_allowed = nightly_features_allowed;
+        result?;
+    }
+    Ok(config)
+}
+
+/// The cargo that ran us (as `cargo quickbuild`), or else the first one on the `PATH`.
+pub fn cargo() -> OsString {
+    std::env::var_os("CARGO").unwrap_or_else(|| OsString::from("cargo"))
+}
+
+/// The workspace in the current directory.
+pub fn current_workspace(config: &Config) -> anyhow::Result<Workspace<'_>> {
+    Workspace::new(&Path::new("Cargo.toml").canonicalize()?, config)
+}
+
+/// Build any missing layers that `options` needs, unpack them into the workspace's target dir,
+/// and then hand over to `cargo {subcommand} {cargo_args}`.
+///
+/// The layers depend on `options.build_config.mode`: `check`, `clippy` and `doc` use check
+/// layers, and `test` also needs the dev-dependencies of the requested packages. The target dir
+/// is the one that cargo would use (so `CARGO_TARGET_DIR` and `build.target-dir` are respected),
+/// even when we're run from a member's directory. It can already contain earlier builds.
+pub fn prebuild_and_run(
+    ws: &Workspace,
+    options: &CompileOptions,
+    subcommand: &str,
+    cargo_args: Vec<String>,
+) -> anyhow::Result<()> {
+    let interner = UnitInterner::new();
+    let workspace_resolve = create_resolve(ws, options, &interner)?;
+    let repo = Repo::from_env()?;
+
+    // Each requested target (`--target a --target b`) is resolved and built separately. Host
+    // layers are the same for every target, so they are only built (and unpacked) once.
+    let mut layers = BTreeMap::new();
+    for kind in &options.build_config.requested_kinds {
+        let resolve = create_quick_resolve(ws, options, &workspace_resolve, *kind)?;
+
+        let root_packages = resolve.requested_packages(&options.spec)?;
+        build_missing_packages(&resolve, &repo, &root_packages, options.build_config.jobs)?;
+        for root_package in root_packages {
+            // FIXME: assert that we've not been asked to build a proc-macro crate.
+            for description in
+                layers_of_deps(&resolve, root_package, BuildFor(FeaturesFor::NormalOrDev))
+            {
+                layers.insert(description.pretty_digest(), description);
+            }
+        }
+    }
+    let here = PathBuf::from(".");
+    let target_dir = ws.target_dir().into_path_unlocked();
+
+    let layers: Vec<_> = layers.into_values().collect();
+    unpack_tarballs(&repo, &layers, &target_dir, None).context("unpacking dependencies")?;
+
+    let stdout_file = repo.write_cargo_log(subcommand, "stdout")?;
+    let stderr_file = repo.write_cargo_log(subcommand, "stderr")?;
+    let mut cargo = command([cargo()]);
+    cargo.arg(subcommand).args(cargo_args);
+    cargo
+        .current_dir(&here)
+        .try_execute_tee(stdout_file, stderr_file)?;
+
+    Ok(())
+}
@@ -0,0 +1,177 @@
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+use anyhow::Result;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+
+/// How blobs are compressed.
+///
+/// Blobs are always stored as `{sha256}.blob`, whatever their compression, so that they can be
+/// shared between differently-configured machines. Each blob starts with a byte that says how the
+/// rest of it is compressed, because its contents can't be trusted to say so: an uncompressed
+/// `.gz` file looks just like a gzipped blob. The compression that a layer's new blobs used is
+/// also recorded in `{digest}.stats.json`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Configured by `CARGO_QUICK_COMPRESSION` (`none`, `gzip` or `zstd`). Defaults to `zstd`.
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("CARGO_QUICK_COMPRESSION") {
+            Ok(compression) => compression.parse(),
+            Err(_) => Ok(Compression::Zstd),
+        }
+    }
+
+    /// Write the header byte to `inner`, and wrap it in the right compressor.
+    pub fn encoder<W: Write>(self, mut inner: W) -> io::Result<Encoder<W>> {
+        inner.write_all(&[self.header()])?;
+        Ok(match self {
+            Compression::None => Encoder::None(inner),
+            Compression::Gzip => {
+                Encoder::Gzip(GzEncoder::new(inner, flate2::Compression::default()))
+            }
+            Compression::Zstd => {
+                Encoder::Zstd(zstd::Encoder::new(inner, zstd::DEFAULT_COMPRESSION_LEVEL)?)
+            }
+        })
+    }
+
+    /// Read the header byte from `reader`, and wrap the rest of it in the right decompressor.
+    pub fn decoder<'a>(mut reader: impl Read + Send + 'a) -> io::Result<Box<dyn Read + Send + 'a>> {
+        let mut header = [0];
+        reader.read_exact(&mut header)?;
+        Ok(match Compression::from_header(header[0])? {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(GzDecoder::new(reader)),
+            Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        })
+    }
+
+    fn header(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Gzip => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    fn from_header(header: u8) -> io::Result<Self> {
+        match header {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Gzip),
+            2 => Ok(Compression::Zstd),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown compression header {header:#04x}"),
+            )),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => anyhow::bail!("unknown compression {s:?} (expected none, gzip or zstd)"),
+        }
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        })
+    }
+}
+
+/// A compressing writer. Call `finish()` to write the end of the stream.
+pub enum Encoder<W: Write> {
+    None(W),
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::None(inner) => Ok(inner),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::None(inner) => inner.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::None(inner) => inner.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let contents: Vec<u8> = (0..100_000u32)
+            .flat_map(|i| (i % 251).to_le_bytes())
+            .collect();
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let mut encoder = compression.encoder(Vec::new())?;
+            encoder.write_all(&contents)?;
+            let compressed = encoder.finish()?;
+            if compression != Compression::None {
+                assert!(compressed.len() < contents.len() / 2, "{compression:?}");
+            }
+
+            let mut decompressed = Vec::new();
+            Compression::decoder(compressed.as_slice())?.read_to_end(&mut decompressed)?;
+            assert_eq!(decompressed, contents, "{compression:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn uncompressed_blobs_that_look_compressed() -> Result<()> {
+        let mut gzipped = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzipped.write_all(b"a .gz file in OUT_DIR")?;
+        let contents = gzipped.finish()?;
+
+        let mut encoder = Compression::None.encoder(Vec::new())?;
+        encoder.write_all(&contents)?;
+        let stored = encoder.finish()?;
+
+        let mut decompressed = Vec::new();
+        Compression::decoder(stored.as_slice())?.read_to_end(&mut decompressed)?;
+        assert_eq!(decompressed, contents);
+        Ok(())
+    }
+}
@@ -1,16 +1,26 @@
+use anyhow::Context;
+use cargo::core::profiles::{Lto, Profile, ProfileRoot};
 use cargo::core::resolver::features::FeaturesFor;
 use crypto_hash::hex_digest;
 use crypto_hash::Algorithm;
 
 use cargo::core::PackageId;
+use serde::{Deserialize, Serialize};
 
-use crate::quick_resolve::BuildFor;
+use crate::build_flags::BuildFlags;
 use crate::quick_resolve::QuickResolve;
+use crate::quick_resolve::{BuildFor, LayerMode};
+use crate::toolchain::Toolchain;
 
 /// A self-contained description of a package build configuration
 pub struct PackageDescription {
     package_id: PackageId,
     build_for: BuildFor,
+    profile_name: String,
+    toolchain: Toolchain,
+    flags: BuildFlags,
+    features: Vec<String>,
+    mode: LayerMode,
     cargo_toml_deps: String,
 }
 
@@ -20,27 +30,75 @@ impl PackageDescription {
         package_id: PackageId,
         build_for: BuildFor,
     ) -> Self {
-        let cargo_toml_deps = packages_to_cargo_toml_contents(resolve, package_id, build_for);
+        let (mode, toolchain, flags) = match build_for.0 {
+            FeaturesFor::NormalOrDev => (resolve.mode, &resolve.toolchain, &resolve.flags),
+            FeaturesFor::HostDep => (
+                LayerMode::Build,
+                &resolve.host_toolchain,
+                &resolve.host_flags,
+            ),
+        };
+        let cargo_toml_deps =
+            packages_to_cargo_toml_contents(resolve, package_id, build_for, mode, toolchain);
         Self {
             package_id,
             build_for,
+            profile_name: resolve.profile.name.to_string(),
+            toolchain: toolchain.clone(),
+            flags: flags.clone(),
+            features: resolve
+                .workspace_resolve
+                .targeted_resolve
+                .features(package_id)
+                .iter()
+                .map(|feature| feature.to_string())
+                .collect(),
+            mode,
             cargo_toml_deps,
         }
     }
     pub fn pretty_digest(&self) -> String {
-        let digest = hex_digest(Algorithm::SHA256, self.cargo_toml_deps.as_bytes());
+        let digest = hex_digest(
+            Algorithm::SHA256,
+            format!("{}\n{}", self.cargo_toml_deps, self.cargo_config_toml()).as_bytes(),
+        );
         let package_name = self.package_id.name();
         let package_version = self.package_id.version();
-        let build_for = match self.build_for.0 {
-            FeaturesFor::NormalOrDev => "target",
-            FeaturesFor::HostDep => "host",
-        };
+        let build_for = self.build_for_name();
 
         format!("{package_name}-{package_version}-{build_for}-{digest}")
     }
+    fn build_for_name(&self) -> &'static str {
+        match self.build_for.0 {
+            FeaturesFor::NormalOrDev => "target",
+            FeaturesFor::HostDep => "host",
+        }
+    }
     pub fn cargo_toml_deps(&self) -> &str {
         &self.cargo_toml_deps
     }
+    /// Whether the generated Cargo.toml has an (empty) build script, so that its
+    /// build-dependencies get built.
+    pub fn has_build_script(&self) -> bool {
+        self.cargo_toml_deps
+            .lines()
+            .any(|line| line == BUILD_SCRIPT)
+    }
+    pub fn mode(&self) -> LayerMode {
+        self.mode
+    }
+    pub fn profile_name(&self) -> &str {
+        &self.profile_name
+    }
+    pub fn toolchain(&self) -> &Toolchain {
+        &self.toolchain
+    }
+    pub fn flags(&self) -> &BuildFlags {
+        &self.flags
+    }
+    pub fn cargo_config_toml(&self) -> String {
+        self.flags.cargo_config_toml()
+    }
 }
 
 impl core::fmt::Debug for PackageDescription {
@@ -52,6 +110,70 @@ impl core::fmt::Debug for PackageDescription {
     }
 }
 
+/// A `PackageDescription` as it is stored alongside its layer, as `{digest}.description.json`, so
+/// that repo tooling can work with layers without resolving anything. Cargo's types need a
+/// resolve to deserialize, so this uses plain strings.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredDescription {
+    /// The `pretty_digest()` that the layer is stored under.
+    pub digest: String,
+    pub name: String,
+    pub version: String,
+    /// e.g. `registry+https://github.com/rust-lang/crates.io-index`.
+    pub source: String,
+    /// `target` or `host`.
+    pub build_for: String,
+    pub profile: String,
+    /// Layers from before there were check layers are all build layers.
+    #[serde(default)]
+    pub mode: LayerMode,
+    pub features: Vec<String>,
+    /// The generated `Cargo.toml` that the layer was built with.
+    pub cargo_toml: String,
+    /// The generated `.cargo/config.toml` that the layer was built with.
+    pub cargo_config_toml: String,
+    pub toolchain: Toolchain,
+    /// When the layer was built, in RFC 3339 format.
+    pub created_at: String,
+    /// The digests of the layers that were unpacked before building this one.
+    pub deps: Vec<String>,
+}
+
+impl StoredDescription {
+    pub fn new(description: &PackageDescription, deps: &[PackageDescription]) -> Self {
+        let package_id = description.package_id;
+        Self {
+            digest: description.pretty_digest(),
+            name: package_id.name().to_string(),
+            version: package_id.version().to_string(),
+            source: package_id.source_id().as_url().to_string(),
+            build_for: description.build_for_name().to_string(),
+            profile: description.profile_name.clone(),
+            mode: description.mode,
+            features: description.features.clone(),
+            cargo_toml: description.cargo_toml_deps.clone(),
+            cargo_config_toml: description.cargo_config_toml(),
+            toolchain: description.toolchain.clone(),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            deps: deps.iter().map(|dep| dep.pretty_digest()).collect(),
+        }
+    }
+
+    /// How long ago the layer was built.
+    pub fn age(&self) -> anyhow::Result<std::time::Duration> {
+        let created_at = chrono::DateTime::parse_from_rfc3339(&self.created_at)
+            .with_context(|| format!("parsing created_at {:?}", self.created_at))?;
+        // Clocks can disagree between machines, so layers from the future are brand new.
+        Ok(
+            (chrono::Utc::now() - created_at.with_timezone(&chrono::Utc))
+                .to_std()
+                .unwrap_or_default(),
+        )
+    }
+}
+
+const BUILD_SCRIPT: &str = "build = \"build.rs\"";
+
 /// Generate the contents of a Cargo.toml file that can be used for building this package.
 ///
 /// FIXME: this does not do the right thing for proc-macro crates currently, and I'm not sure how
@@ -60,6 +182,8 @@ fn packages_to_cargo_toml_contents<'cfg>(
     resolve: &QuickResolve<'cfg, '_>,
     package_id: PackageId,
     build_for: BuildFor,
+    mode: LayerMode,
+    toolchain: &Toolchain,
 ) -> String {
     let name = package_id.name();
     let version = package_id.version();
@@ -76,20 +200,84 @@ fn packages_to_cargo_toml_contents<'cfg>(
             .filter(|(_, build_for)| build_for.0 == FeaturesFor::HostDep)
             .copied(),
     );
+    let profile = profile_to_string(&resolve.profile);
+    // The fingerprint includes the target, so layers for different targets get different digests.
+    let toolchain = toolchain.fingerprint();
+    // Cargo only builds build-dependencies for packages that have a build script, so give the
+    // scratchpad an empty one (see `overwrite_manifest()`). Otherwise host layers would be empty,
+    // and every layer that needed them would contain its own copy.
+    // Build layers keep the digests that they had before there were check layers.
+    let mode = match mode {
+        LayerMode::Build => String::new(),
+        LayerMode::Check => "# cargo check\n".to_string(),
+    };
+    let build_script = if build_deps.is_empty() {
+        String::new()
+    } else {
+        format!("{BUILD_SCRIPT}\n")
+    };
 
     format!(
         "# {name} {version}\n\
+        # {toolchain}\n\
+        {mode}\
         \n\
         [package]\n\
         name = \"cargo-quickbuild-scratchpad\"\n\
         version = \"0.1.0\"\n\
         edition = \"2021\"\n\
+        {build_script}\
         \n\
         [dependencies]\n\
         {target_deps}\n\
         \n\
         [build-dependencies]\n\
         {build_deps}\n\
+        \n\
+        {profile}\
+        ",
+    )
+}
+
+/// Write out the settings of the requested profile, so that each layer is built the same way as
+/// the final `cargo build` will build it, and so that different profiles get different digests.
+///
+/// FIXME: per-package overrides (`[profile.dev.package.foo]`) and `build-override` are ignored.
+fn profile_to_string(profile: &Profile) -> String {
+    let name = profile.name;
+    let inherits = match (name.as_str(), profile.root) {
+        ("dev" | "release", _) => String::new(),
+        (_, ProfileRoot::Release) => "inherits = \"release\"\n".to_string(),
+        (_, ProfileRoot::Debug) => "inherits = \"dev\"\n".to_string(),
+    };
+    let opt_level = match profile.opt_level.parse::<u32>() {
+        Ok(level) => level.to_string(),
+        Err(_) => format!("{:?}", profile.opt_level.as_str()),
+    };
+    let debug = profile.debuginfo.unwrap_or(0);
+    let lto = match profile.lto {
+        Lto::Off => "\"off\"".to_string(),
+        Lto::Bool(lto) => lto.to_string(),
+        Lto::Named(lto) => format!("{:?}", lto.as_str()),
+    };
+    let codegen_units = match profile.codegen_units {
+        Some(codegen_units) => format!("codegen-units = {codegen_units}\n"),
+        None => String::new(),
+    };
+    let panic = profile.panic;
+    let debug_assertions = profile.debug_assertions;
+    let overflow_checks = profile.overflow_checks;
+
+    format!(
+        "[profile.{name}]\n\
+        {inherits}\
+        opt-level = {opt_level}\n\
+        debug = {debug}\n\
+        lto = {lto}\n\
+        {codegen_units}\
+        panic = \"{panic}\"\n\
+        debug-assertions = {debug_assertions}\n\
+        overflow-checks = {overflow_checks}\n\
         ",
     )
 }
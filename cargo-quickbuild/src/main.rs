@@ -1,42 +1,70 @@
 mod archive;
+mod build_flags;
 mod builder;
 mod commands;
+mod compression;
 mod description;
-mod pax;
+mod manifest;
 mod quick_resolve;
+mod remote;
 mod repo;
 mod resolve;
 mod scheduler;
 mod stats;
+mod storage;
+mod toolchain;
 pub mod util;
 mod vendor;
 
-use anyhow::Result;
+use cargo::util::command_prelude::{App, AppSettings};
 
-fn main() -> Result<()> {
+fn cli(bin_name: &'static str) -> App {
+    App::new("cargo-quickbuild")
+        .bin_name(bin_name)
+        .version(env!("CARGO_PKG_VERSION"))
+        .about(
+            "A wrapper around `cargo build`, using precompiled crates.\n\
+            \n\
+            With no subcommand, this behaves like `build`.",
+        )
+        .setting(AppSettings::DeriveDisplayOrder)
+        .subcommands(commands::builtin())
+}
+
+fn main() {
     // hack: disable target/.rustc_info.json nonsense.
     std::env::set_var("CARGO_CACHE_RUSTC_INFO", "0");
 
     pretty_env_logger::init();
 
+    // `cargo quickbuild build` runs us as `cargo-quickbuild quickbuild build`, and `cargo quick build`
+    // (the bootstrapping tool in ../cargo-quick) runs us as `cargo-quickbuild quick build`.
     let mut args: Vec<_> = std::env::args().collect();
-    if args[1] == "quickbuild" {
-        args.remove(1);
-    }
-    if args.is_empty() {
-        args.push("build".to_string())
-    }
+    let bin_name = match args.get(1).map(String::as_str) {
+        Some("quickbuild") => {
+            args.remove(1);
+            "cargo quickbuild"
+        }
+        Some("quick") => {
+            args.remove(1);
+            "cargo quick"
+        }
+        _ => "cargo-quickbuild",
+    };
+
+    // Clap prints its own usage errors (and --help/--version) and exits with an appropriate code.
+    let matches = cli(bin_name).get_matches_from(args);
+    let result = match matches.subcommand() {
+        Some((cmd, sub_matches)) => {
+            let exec = commands::builtin_exec(cmd).expect("clap only accepts known subcommands");
+            exec(sub_matches)
+        }
+        None => commands::cmd_build::exec(&commands::cmd_build::cli().get_matches_from(["build"])),
+    };
 
-    match args[1].as_str() {
-        "build" => commands::cmd_build::exec(&args[1..]),
-        "install" => commands::cmd_install::exec(&args[1..]),
-        "repo" => commands::cmd_repo::exec(&args[1..]),
-        // FIXME:
-        // * I intend to use `cargo quick` as a thin bootstrapping tool. If we are being called
-        //   from `cargo quick`, we should adjust our usage messages appropriately.
-        // * We probably shouldn't panic here - the user doesn't need a backtrace. Is it possible
-        //   to configure anyhow::Result so that it sets a nonzero exit code, but doesn't print a
-        //   backtrace, or do I need to wrap it somehow (can I copy-pasta whatever `cargo` does?)
-        _ => panic!("Usage: cargo quickbuild build"),
+    if let Err(err) = result {
+        // Match cargo's error reporting: no backtrace, just the chain of causes.
+        eprintln!("error: {err:?}");
+        std::process::exit(101);
     }
 }
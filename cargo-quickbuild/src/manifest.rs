@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+
+/// The contents of a layer: every path that the package's build added to `target/`.
+///
+/// File contents aren't stored in the manifest. They are content-addressed blobs in the repo
+/// (see `Repo::write_blob()`), so identical files in different layers are only stored once.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Manifest {
+    /// Keyed by path relative to the project root (e.g. `target/debug/deps/libfoo-1234.rlib`).
+    pub entries: BTreeMap<PathBuf, ManifestEntry>,
+}
+
+impl Manifest {
+    /// The total (uncompressed) size of the layer's files.
+    pub fn size(&self) -> u64 {
+        self.entries
+            .values()
+            .map(|entry| match entry.kind {
+                EntryKind::File { size, .. } => size,
+                _ => 0,
+            })
+            .sum()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    #[serde(flatten)]
+    pub kind: EntryKind,
+    #[serde(with = "filetime_as_string")]
+    pub mtime: FileTime,
+    /// Unix permission bits.
+    pub mode: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum EntryKind {
+    /// `sha256` is the digest of the (uncompressed) contents, with paths remapped to the stable
+    /// scratch dir, and `size` is their length. `remapped` says whether there were any paths to
+    /// remap, and so whether they need remapping again when the file is unpacked.
+    File {
+        sha256: String,
+        size: u64,
+        #[serde(default)]
+        remapped: bool,
+    },
+    Dir,
+    Symlink {
+        target: PathBuf,
+    },
+}
+
+/// `{seconds}.{nanoseconds}`, like the pax `mtime` extension, so that mtimes survive the round
+/// trip exactly (cargo's fingerprinting compares them).
+pub(crate) mod filetime_as_string {
+    use filetime::FileTime;
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(mtime: &FileTime, serializer: S) -> Result<S::Ok, S::Error> {
+        format!("{}.{:09}", mtime.unix_seconds(), mtime.nanoseconds()).serialize(serializer)
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<FileTime, D::Error> {
+        let mtime = String::deserialize(deserializer)?;
+        let (seconds, nanos) = mtime
+            .split_once('.')
+            .ok_or_else(|| D::Error::custom(format!("bad mtime {mtime:?}")))?;
+        Ok(FileTime::from_unix_time(
+            seconds.parse().map_err(D::Error::custom)?,
+            nanos.parse().map_err(D::Error::custom)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() -> anyhow::Result<()> {
+        let mut manifest = Manifest::default();
+        manifest.entries.insert(
+            PathBuf::from("target/debug/deps"),
+            ManifestEntry {
+                kind: EntryKind::Dir,
+                mtime: FileTime::from_unix_time(1_650_000_000, 5),
+                mode: 0o755,
+            },
+        );
+        manifest.entries.insert(
+            PathBuf::from("target/debug/deps/libfoo.rlib"),
+            ManifestEntry {
+                kind: EntryKind::File {
+                    sha256: "ab".repeat(32),
+                    size: 1234,
+                    remapped: false,
+                },
+                mtime: FileTime::from_unix_time(1_650_000_001, 123_456_789),
+                mode: 0o644,
+            },
+        );
+        let json = serde_json::to_string(&manifest)?;
+        assert!(json.contains(r#""mtime":"1650000000.000000005""#), "{json}");
+        let parsed: Manifest = serde_json::from_str(&json)?;
+        assert_eq!(parsed.entries, manifest.entries);
+        Ok(())
+    }
+}
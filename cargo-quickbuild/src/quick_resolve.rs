@@ -1,11 +1,12 @@
 use std::cmp::Ordering;
 use std::collections::BTreeSet;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use anyhow::Result;
-use cargo::core::compiler::RustcTargetData;
+use cargo::core::compiler::{CompileKind, CompileMode, RustcTargetData};
 
 use cargo::core::dependency::DepKind;
+use cargo::core::profiles::{Profile, Profiles};
 use cargo::core::resolver::features::FeaturesFor;
 use cargo::core::Package;
 use cargo::core::{PackageId, Workspace};
@@ -13,7 +14,10 @@ use cargo::ops::WorkspaceResolve;
 use cargo::ops::{CompileOptions, Packages};
 
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 
+use crate::build_flags::BuildFlags;
+use crate::toolchain::Toolchain;
 use crate::vendor::tree::graph::Graph;
 use crate::vendor::tree::{Charset, EdgeKind, Prefix, Target, TreeOptions};
 
@@ -27,17 +31,53 @@ impl Eq for BuildFor {}
 // Arbitrarily impl Ord so that I can put it in a BTreeMap
 impl PartialOrd for BuildFor {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(match (self.0, other.0) {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for BuildFor {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.0, other.0) {
             (FeaturesFor::HostDep, FeaturesFor::HostDep) => Ordering::Equal,
             (FeaturesFor::NormalOrDev, FeaturesFor::NormalOrDev) => Ordering::Equal,
             (FeaturesFor::NormalOrDev, FeaturesFor::HostDep) => Ordering::Less,
             (FeaturesFor::HostDep, FeaturesFor::NormalOrDev) => Ordering::Greater,
-        })
+        }
     }
 }
-impl Ord for BuildFor {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
+
+/// What kind of artifacts a layer holds. `cargo check` (and `clippy` and `doc`) only need
+/// metadata (`.rmeta`) for dependencies, which `cargo build` doesn't produce, so they need
+/// different layers.
+///
+/// Host dependencies (build scripts, proc-macros and their deps) are always fully built, so
+/// their layers are always `Build` layers, and shared between all modes.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LayerMode {
+    #[default]
+    Build,
+    Check,
+}
+
+impl LayerMode {
+    pub fn new(mode: CompileMode) -> Self {
+        match mode {
+            CompileMode::Check { .. } | CompileMode::Doc { .. } => LayerMode::Check,
+            CompileMode::Build
+            | CompileMode::Test
+            | CompileMode::Bench
+            | CompileMode::Doctest
+            | CompileMode::Docscrape
+            | CompileMode::RunCustomBuild => LayerMode::Build,
+        }
+    }
+
+    /// The cargo subcommand that builds the layer.
+    pub fn cargo_subcommand(self) -> &'static str {
+        match self {
+            LayerMode::Build => "build",
+            LayerMode::Check => "check",
+        }
     }
 }
 
@@ -50,9 +90,40 @@ where
     pub ws: &'a Workspace<'cfg>,
     pub workspace_resolve: &'a WorkspaceResolve<'cfg>,
     pub graph: Graph<'a>,
+    /// The profile that was requested on the command line (`--release`, `--profile`, ...).
+    pub profile: Profile,
+    /// The toolchain and flags for layers that are built for the target (`--target`, or the host
+    /// if there isn't one).
+    pub toolchain: Toolchain,
+    pub flags: BuildFlags,
+    /// The toolchain and flags for host dependencies (build scripts, proc-macros and their deps).
+    /// These are always built for the host, and only differ from `toolchain` and `flags` when
+    /// cross-compiling, so host layers are shared with native builds.
+    pub host_toolchain: Toolchain,
+    pub host_flags: BuildFlags,
+    /// Derived from the `CompileMode` that was requested (`cargo check`, `cargo test`, ...).
+    pub mode: LayerMode,
 }
 
 impl<'cfg, 'a> QuickResolve<'cfg, 'a> {
+    /// The workspace members that were selected on the command line (`-p`, `--workspace`,
+    /// `--exclude`), or the default members if none were.
+    pub fn requested_packages(&self, spec: &Packages) -> Result<Vec<PackageId>> {
+        Ok(spec
+            .get_packages(self.ws)?
+            .into_iter()
+            .map(|pkg| pkg.package_id())
+            .collect())
+    }
+
+    /// Workspace members are built by the final cargo command, never as layers, because they
+    /// change all the time.
+    pub fn is_workspace_member(&self, package_id: PackageId) -> bool {
+        self.ws
+            .members()
+            .any(|member| member.package_id() == package_id)
+    }
+
     pub fn recursive_deps_including_self(
         &self,
         package_id: PackageId,
@@ -65,6 +136,33 @@ impl<'cfg, 'a> QuickResolve<'cfg, 'a> {
         deps
     }
 
+    /// Like `recursive_deps_including_self()`, but also including the package's own
+    /// dev-dependencies and everything that they depend on, for building its tests, examples and
+    /// benches. These are only in the graph if the build needs them (see `create_quick_resolve()`).
+    ///
+    /// Only the package that we're building has its dev-dependencies built. Dependencies'
+    /// dev-dependencies are never needed, so layers never include them.
+    pub fn recursive_deps_including_dev_deps(
+        &self,
+        package_id: PackageId,
+        initial_build_for: BuildFor,
+    ) -> BTreeSet<(PackageId, BuildFor)> {
+        let mut deps = self.recursive_deps_including_self(package_id, initial_build_for);
+        let dev_edge = EdgeKind::Dep(DepKind::Development);
+        for node_index in self.graph.indexes_from_ids(&[package_id]) {
+            for idx in self.graph.connected_nodes(node_index, &dev_edge) {
+                let dev_dep = self.graph.package_id_for_index(idx);
+                let build_for = if self.graph.package_for_id(dev_dep).proc_macro() {
+                    BuildFor(FeaturesFor::HostDep)
+                } else {
+                    initial_build_for
+                };
+                deps.extend(self.recursive_deps_including_self(dev_dep, build_for));
+            }
+        }
+        deps
+    }
+
     fn _recursive_deps(
         &self,
         initial_package_id: PackageId,
@@ -99,8 +197,10 @@ impl<'cfg, 'a> QuickResolve<'cfg, 'a> {
                                         BuildFor(FeaturesFor::NormalOrDev)
                                     }
                                 }
+                                // dev-dependencies are only followed from the root, by
+                                // recursive_deps_including_dev_deps()
                                 (FeaturesFor::NormalOrDev, DepKind::Development) => {
-                                    todo!("I don't think we want to support Development dependencies yet");
+                                    unreachable!("dev-dependencies of dependencies are never built")
                                 }
                                 // build dep links turns all children into build deps
                                 (FeaturesFor::NormalOrDev, DepKind::Build) => {
@@ -121,14 +221,13 @@ impl<'cfg, 'a> QuickResolve<'cfg, 'a> {
             }
             indexes = layer
                 .iter()
-                .map(|(package_id, build_for)| {
+                .flat_map(|(package_id, build_for)| {
                     self.graph
                         .indexes_from_ids(&[*package_id])
                         .into_iter()
                         .map(|idx| (idx, *build_for))
                         .collect_vec()
                 })
-                .flatten()
                 .collect_vec();
             deps.extend(layer);
         }
@@ -136,11 +235,17 @@ impl<'cfg, 'a> QuickResolve<'cfg, 'a> {
     }
 }
 
+/// Resolve the layers for building for `kind`, which must be one of the requested kinds
+/// (`--target`). Multi-target builds resolve once per kind; their host layers have the same
+/// digests, so they are only built once.
 pub fn create_quick_resolve<'cfg, 'a>(
     ws: &'a Workspace<'cfg>,
     options: &CompileOptions,
     workspace_resolve: &'a cargo::ops::WorkspaceResolve<'cfg>,
+    kind: CompileKind,
 ) -> Result<QuickResolve<'cfg, 'a>, anyhow::Error> {
+    // All of the requested kinds are needed here, because cargo doesn't apply rustflags to host
+    // dependencies when cross-compiling, unless the host is also one of the requested kinds.
     let requested_kinds = &options.build_config.requested_kinds;
     let target_data = RustcTargetData::new(ws, requested_kinds)?;
     let package_map: HashMap<PackageId, &Package> = workspace_resolve
@@ -149,16 +254,26 @@ pub fn create_quick_resolve<'cfg, 'a>(
         .map(|pkg| (pkg.package_id(), pkg))
         .collect();
     let packages = clone_packages(&options.spec);
+    let mut edge_kinds: HashSet<EdgeKind> = [
+        EdgeKind::Dep(DepKind::Normal),
+        EdgeKind::Dep(DepKind::Build),
+    ]
+    .into_iter()
+    .collect();
+    // This matches `create_resolve()`: features are only resolved for dev-dependencies if the
+    // build needs them (e.g. `cargo test`, or `cargo build --tests`).
+    if options.filter.need_dev_deps(options.build_config.mode) {
+        edge_kinds.insert(EdgeKind::Dep(DepKind::Development));
+    }
+    let target = match kind {
+        CompileKind::Host => Target::Host,
+        CompileKind::Target(target) => Target::Specific(vec![target.rustc_target().to_string()]),
+    };
     let opts = TreeOptions {
         cli_features: options.cli_features.clone(),
         packages,
-        target: Target::Host,
-        edge_kinds: [
-            EdgeKind::Dep(DepKind::Normal),
-            EdgeKind::Dep(DepKind::Build),
-        ]
-        .into_iter()
-        .collect(),
+        target,
+        edge_kinds,
         invert: Default::default(),
         pkgs_to_prune: Default::default(),
         prefix: Prefix::None,
@@ -177,15 +292,22 @@ pub fn create_quick_resolve<'cfg, 'a>(
         &options.spec.to_package_id_specs(ws)?,
         &options.cli_features,
         &target_data,
-        requested_kinds,
+        &[kind],
         package_map,
         &opts,
     )
     .unwrap();
+    let profile = Profiles::new(ws, options.build_config.requested_profile)?.base_profile();
     let resolve = QuickResolve {
         ws,
         workspace_resolve,
         graph,
+        profile,
+        toolchain: Toolchain::new(&target_data, kind)?,
+        flags: BuildFlags::new(ws.config(), &target_data, kind),
+        host_toolchain: Toolchain::new(&target_data, CompileKind::Host)?,
+        host_flags: BuildFlags::new(ws.config(), &target_data, CompileKind::Host),
+        mode: LayerMode::new(options.build_config.mode),
     };
     Ok(resolve)
 }
@@ -270,6 +392,12 @@ mod tests {
             ws: &ws,
             workspace_resolve: &workspace_resolve,
             graph,
+            profile: Profiles::new(&ws, options.build_config.requested_profile)?.base_profile(),
+            toolchain: Toolchain::new(&target_data, requested_kinds[0])?,
+            flags: BuildFlags::new(&config, &target_data, requested_kinds[0]),
+            host_toolchain: Toolchain::new(&target_data, CompileKind::Host)?,
+            host_flags: BuildFlags::new(&config, &target_data, CompileKind::Host),
+            mode: LayerMode::Build,
         };
 
         assert_eq!(target_dep_names_for_package(&resolve, "libc"), &["libc"]);
@@ -0,0 +1,173 @@
+use std::io::Read;
+
+use anyhow::Result;
+
+use super::{put_all, Request};
+use crate::storage::{Staged, Storage};
+
+/// A plain HTTP(S) server that stores files by name, e.g. nginx with WebDAV `PUT` enabled, or an
+/// S3 bucket that allows anonymous reads.
+///
+/// Keys are file names like `{pretty_digest}.tar`, appended to `base_url`.
+#[derive(Debug, Clone)]
+pub struct HttpRemote {
+    base_url: String,
+    token: Option<String>,
+}
+
+impl HttpRemote {
+    /// Configured by `CARGO_QUICK_REMOTE_URL`, and optionally `CARGO_QUICK_REMOTE_TOKEN`, which is
+    /// sent as a bearer token.
+    pub fn from_env() -> Option<Self> {
+        let base_url = std::env::var("CARGO_QUICK_REMOTE_URL").ok()?;
+        let token = std::env::var("CARGO_QUICK_REMOTE_TOKEN").ok();
+        Some(Self::new(base_url, token))
+    }
+
+    pub fn new(base_url: String, token: Option<String>) -> Self {
+        let base_url = base_url.trim_end_matches('/').to_owned();
+        HttpRemote { base_url, token }
+    }
+
+    fn request(&self, key: &str) -> Request {
+        let mut headers = Vec::new();
+        if let Some(token) = &self.token {
+            headers.push(format!("Authorization: Bearer {token}"));
+        }
+        Request {
+            url: format!("{}/{key}", self.base_url),
+            headers,
+        }
+    }
+}
+
+impl Storage for HttpRemote {
+    fn has(&self, key: &str) -> Result<bool> {
+        self.request(key).head()
+    }
+
+    fn read(&self, key: &str) -> Result<Option<Box<dyn Read + Send>>> {
+        self.request(key).get_to_temp_file(key)
+    }
+
+    fn write(&self, key: &str) -> Result<Staged> {
+        Staged::new(&std::env::temp_dir(), key)
+    }
+
+    fn commit(&self, staged: Vec<Staged>) -> Result<()> {
+        put_all(staged, |key, path| self.request(key).put(path))
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        anyhow::bail!("plain HTTP remotes can't be listed")
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.request(key).delete()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    type Files = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /// A tiny HTTP/1.1 server that keeps PUT bodies in memory, and closes every connection after
+    /// one response.
+    fn serve() -> (String, Files) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/layers", listener.local_addr().unwrap());
+        let files = Arc::new(Mutex::new(HashMap::new()));
+        let server_files = files.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut parts = request_line.split_whitespace();
+                let method = parts.next().unwrap().to_owned();
+                let path = parts.next().unwrap().to_owned();
+
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    let header = header.trim_end();
+                    if header.is_empty() {
+                        break;
+                    }
+                    let (name, value) = header.split_once(": ").unwrap();
+                    match name.to_ascii_lowercase().as_str() {
+                        "content-length" => content_length = value.parse().unwrap(),
+                        "expect" => stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").unwrap(),
+                        _ => {}
+                    }
+                }
+
+                let mut files = server_files.lock().unwrap();
+                let (status, body) = match method.as_str() {
+                    "PUT" => {
+                        let mut body = vec![0; content_length];
+                        reader.read_exact(&mut body).unwrap();
+                        files.insert(path, body);
+                        ("201 Created", Vec::new())
+                    }
+                    "DELETE" => {
+                        files.remove(&path);
+                        ("204 No Content", Vec::new())
+                    }
+                    _ => match files.get(&path) {
+                        Some(body) => ("200 OK", body.clone()),
+                        None => ("404 Not Found", Vec::new()),
+                    },
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .unwrap();
+                if method != "HEAD" {
+                    stream.write_all(&body).unwrap();
+                }
+            }
+        });
+        (url, files)
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let (url, files) = serve();
+        let remote = HttpRemote::new(format!("{url}/"), None);
+
+        assert!(!remote.has("foo.tar")?);
+        assert!(remote.read("foo.tar")?.is_none());
+
+        // Bigger than curl's threshold for sending `Expect: 100-continue`.
+        let contents: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let mut staged = remote.write("foo.tar")?;
+        staged.write_all(&contents)?;
+        remote.commit(vec![staged])?;
+        assert_eq!(files.lock().unwrap()["/layers/foo.tar"], contents);
+
+        assert!(remote.has("foo.tar")?);
+        let mut downloaded = Vec::new();
+        remote
+            .read("foo.tar")?
+            .unwrap()
+            .read_to_end(&mut downloaded)?;
+        assert_eq!(downloaded, contents);
+
+        remote.delete("foo.tar")?;
+        assert!(!remote.has("foo.tar")?);
+        Ok(())
+    }
+}
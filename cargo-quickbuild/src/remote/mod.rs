@@ -0,0 +1,157 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use curl::easy::{Easy, List, ReadError};
+
+use crate::storage::{Staged, Storage};
+
+mod http;
+mod s3;
+
+pub use http::HttpRemote;
+pub use s3::S3Remote;
+
+/// Pick a remote based on the environment: S3 if `CARGO_QUICK_S3_BUCKET` is set, or plain HTTP if
+/// `CARGO_QUICK_REMOTE_URL` is set.
+pub fn from_env() -> Option<Box<dyn Storage>> {
+    if let Some(s3) = S3Remote::from_env() {
+        return Some(Box::new(s3));
+    }
+    if let Some(http) = HttpRemote::from_env() {
+        return Some(Box::new(http));
+    }
+    None
+}
+
+/// A single HTTP request. Each kind of remote is responsible for its own url scheme and auth
+/// headers, and they share the actual transfers.
+struct Request {
+    url: String,
+    headers: Vec<String>,
+}
+
+impl Request {
+    fn easy(&self) -> Result<Easy> {
+        let mut easy = Easy::new();
+        easy.url(&self.url)?;
+        easy.follow_location(true)?;
+        let mut headers = List::new();
+        for header in &self.headers {
+            headers.append(header)?;
+        }
+        easy.http_headers(headers)?;
+        Ok(easy)
+    }
+
+    fn head(&self) -> Result<bool> {
+        let mut easy = self.easy()?;
+        easy.nobody(true)?;
+        easy.perform()
+            .with_context(|| format!("HEAD {}", self.url))?;
+        self.found(&mut easy, "HEAD")
+    }
+
+    /// Download into `out`, returning false if the object doesn't exist (in which case `out` may
+    /// contain an error page).
+    fn get(&self, out: &mut dyn Write) -> Result<bool> {
+        let mut write_error = None;
+        let mut easy = self.easy()?;
+        let result = {
+            let mut transfer = easy.transfer();
+            transfer.write_function(|data| match out.write_all(data) {
+                Ok(()) => Ok(data.len()),
+                Err(e) => {
+                    write_error = Some(e);
+                    // Returning a short count makes curl abort the transfer.
+                    Ok(0)
+                }
+            })?;
+            transfer.perform()
+        };
+        if let Some(e) = write_error {
+            return Err(e).context("writing download");
+        }
+        result.with_context(|| format!("GET {}", self.url))?;
+        self.found(&mut easy, "GET")
+    }
+
+    /// Download into a temporary file, and return it opened for reading.
+    fn get_to_temp_file(&self, key: &str) -> Result<Option<Box<dyn Read + Send>>> {
+        let mut staged = Staged::new(&std::env::temp_dir(), key)?;
+        if !self.get(&mut staged)? {
+            return Ok(None);
+        }
+        let path = staged.finish()?;
+        let file = File::open(&path)?;
+        // The open file stays readable after it is unlinked.
+        std::fs::remove_file(&path)?;
+        Ok(Some(Box::new(file)))
+    }
+
+    fn put(&self, src: &Path) -> Result<()> {
+        let mut file = File::open(src).with_context(|| format!("opening {src:?}"))?;
+        let size = file.metadata()?.len();
+
+        let mut easy = self.easy()?;
+        easy.upload(true)?;
+        easy.in_filesize(size)?;
+        let mut read_error = None;
+        let result = {
+            let mut transfer = easy.transfer();
+            transfer.read_function(|buf| match file.read(buf) {
+                Ok(n) => Ok(n),
+                Err(e) => {
+                    // Returning 0 would look like EOF and upload a truncated object.
+                    read_error = Some(e);
+                    Err(ReadError::Abort)
+                }
+            })?;
+            transfer.perform()
+        };
+        if let Some(e) = read_error {
+            return Err(e).with_context(|| format!("reading {src:?}"));
+        }
+        result.with_context(|| format!("PUT {}", self.url))?;
+        let code = easy.response_code()?;
+        anyhow::ensure!(
+            (200..300).contains(&code),
+            "PUT {} failed with HTTP {code}",
+            self.url
+        );
+        Ok(())
+    }
+
+    fn delete(&self) -> Result<()> {
+        let mut easy = self.easy()?;
+        easy.custom_request("DELETE")?;
+        easy.perform()
+            .with_context(|| format!("DELETE {}", self.url))?;
+        match easy.response_code()? {
+            200..=299 | 404 => Ok(()),
+            code => anyhow::bail!("DELETE {} failed with HTTP {code}", self.url),
+        }
+    }
+
+    /// Interpret the response code of a `HEAD` or `GET`.
+    fn found(&self, easy: &mut Easy, method: &str) -> Result<bool> {
+        match easy.response_code()? {
+            200..=299 => Ok(true),
+            404 | 410 => Ok(false),
+            code => anyhow::bail!("{method} {} failed with HTTP {code}", self.url),
+        }
+    }
+}
+
+/// Upload staged objects in order, removing each staged file once it has been uploaded.
+fn put_all(staged: Vec<Staged>, put: impl Fn(&str, &Path) -> Result<()>) -> Result<()> {
+    for staged in staged {
+        let key = staged.key().to_owned();
+        let path = staged.finish()?;
+        let result = put(&key, &path);
+        let _ = std::fs::remove_file(&path);
+        result?;
+    }
+    Ok(())
+}
@@ -0,0 +1,368 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use super::{blob_key, Repo};
+use crate::description::StoredDescription;
+use crate::manifest::EntryKind;
+use crate::util::units::format_duration;
+
+/// What `Repo::gc()` should evict. A layer is evicted if any of the policies says so.
+#[derive(Debug, Default)]
+pub struct GcPolicy {
+    /// Evict least-recently-used layers until the repo fits in this many bytes.
+    pub max_size: Option<u64>,
+    /// Evict layers that haven't been used for this long.
+    pub max_age: Option<Duration>,
+    /// If there are any, evict layers that none of these `Cargo.lock` files could use.
+    pub lockfiles: Vec<PathBuf>,
+}
+
+#[derive(Debug)]
+pub struct Eviction {
+    pub digest: String,
+    pub reason: String,
+    /// The number of bytes that evicting this layer freed, including blobs that no other layer
+    /// uses.
+    pub freed: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub evicted: Vec<Eviction>,
+    /// Blobs that no layer uses any more, including ones that weren't used by any layer to begin
+    /// with.
+    pub blobs_removed: usize,
+    pub freed: u64,
+    pub remaining: u64,
+}
+
+/// A layer, as far as garbage collection is concerned.
+struct Layer {
+    /// Every key that belongs to the layer: its manifest, sidecars and logs.
+    keys: Vec<String>,
+    size: u64,
+    blobs: BTreeSet<String>,
+    last_access: SystemTime,
+    description: Option<StoredDescription>,
+}
+
+impl Repo {
+    /// Evict layers according to `policy`, and remove any blobs that are left unused.
+    ///
+    /// This should be run on `Repo::exclusive_local_from_env()`, because blobs are committed
+    /// before the manifests that use them, and we don't want to delete blobs from under a build.
+    /// Extracted layers (see `UnpackMode`) are removed along with their layers, but don't count
+    /// towards `max_size`.
+    pub fn gc(&self, policy: &GcPolicy, dry_run: bool) -> Result<GcReport> {
+        let keys = self.storage.list()?;
+        let mut sizes = BTreeMap::new();
+        for key in &keys {
+            sizes.insert(key.as_str(), self.storage.size(key)?);
+        }
+        let mut remaining: u64 = sizes.values().sum();
+
+        let mut layers = BTreeMap::new();
+        for key in &keys {
+            let digest = match key.strip_suffix(".manifest.json") {
+                Some(digest) => digest,
+                None => continue,
+            };
+            let manifest = match self.read_manifest(key).with_context(|| {
+                format!("reading {key} (`repo verify --quarantine` will remove broken layers)")
+            })? {
+                Some(manifest) => manifest,
+                None => continue,
+            };
+            // Without a description, the layer can still be evicted by age or size.
+            let description = self.description(digest).unwrap_or_else(|e| {
+                log::warn!("failed to read the description of {digest}: {e:#}");
+                None
+            });
+            let prefix = format!("{digest}.");
+            let layer_keys: Vec<String> = keys
+                .iter()
+                .filter(|key| key.starts_with(&prefix))
+                .cloned()
+                .collect();
+            let layer = Layer {
+                size: layer_keys.iter().map(|key| sizes[key.as_str()]).sum(),
+                keys: layer_keys,
+                blobs: manifest
+                    .entries
+                    .values()
+                    .filter_map(|entry| match &entry.kind {
+                        EntryKind::File { sha256, .. } => Some(blob_key(sha256)),
+                        _ => None,
+                    })
+                    .collect(),
+                last_access: self.last_access(digest, description.as_ref()),
+                description,
+            };
+            layers.insert(digest.to_owned(), layer);
+        }
+
+        let mut users: BTreeMap<&str, usize> = keys
+            .iter()
+            .filter(|key| key.ends_with(".blob"))
+            .map(|key| (key.as_str(), 0))
+            .collect();
+        for layer in layers.values() {
+            for blob in &layer.blobs {
+                *users.entry(blob).or_default() += 1;
+            }
+        }
+
+        let never_used: BTreeSet<&str> = users
+            .iter()
+            .filter(|(_, count)| **count == 0)
+            .map(|(blob, _)| *blob)
+            .collect();
+        // These go whatever happens, so they count towards `max_size` before any layer does.
+        for blob in &never_used {
+            remaining -= sizes[blob];
+        }
+
+        let mut report = GcReport::default();
+        // Returns the number of bytes freed.
+        let release = |layer: &Layer, users: &mut BTreeMap<&str, usize>| {
+            let mut freed = layer.size;
+            for blob in &layer.blobs {
+                let count = users.get_mut(blob.as_str()).unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    freed += sizes.get(blob.as_str()).copied().unwrap_or(0);
+                }
+            }
+            freed
+        };
+        let mut evict = |digest: &str, reason: String, remaining: &mut u64| {
+            let freed = release(&layers[digest], &mut users);
+            *remaining -= freed;
+            report.evicted.push(Eviction {
+                digest: digest.to_owned(),
+                reason,
+                freed,
+            });
+        };
+
+        let now = SystemTime::now();
+        let reachable = if policy.lockfiles.is_empty() {
+            None
+        } else {
+            Some(reachable_from_lockfiles(&policy.lockfiles, &layers)?)
+        };
+        let mut by_last_access: Vec<&String> = layers.keys().collect();
+        by_last_access.sort_by_key(|digest| layers[*digest].last_access);
+        let mut kept = Vec::new();
+        for digest in by_last_access {
+            let age = now
+                .duration_since(layers[digest].last_access)
+                .unwrap_or_default();
+            if matches!(&reachable, Some(reachable) if !reachable.contains(digest.as_str())) {
+                evict(
+                    digest,
+                    String::from("not used by any lockfile"),
+                    &mut remaining,
+                );
+            } else if policy.max_age.is_some_and(|max_age| age > max_age) {
+                evict(
+                    digest,
+                    format!("not used for {}", format_duration(age)),
+                    &mut remaining,
+                );
+            } else {
+                kept.push((digest, age));
+            }
+        }
+        if let Some(max_size) = policy.max_size {
+            for (digest, age) in kept {
+                if remaining <= max_size {
+                    break;
+                }
+                evict(
+                    digest,
+                    format!("least recently used ({} ago)", format_duration(age)),
+                    &mut remaining,
+                );
+            }
+        }
+
+        let unused_blobs: Vec<&str> = users
+            .iter()
+            .filter(|(blob, count)| **count == 0 && sizes.contains_key(*blob))
+            .map(|(blob, _)| *blob)
+            .collect();
+        report.blobs_removed = unused_blobs.len();
+        report.remaining = remaining;
+        report.freed = sizes.values().sum::<u64>() - remaining;
+
+        if dry_run {
+            return Ok(report);
+        }
+        for eviction in &report.evicted {
+            self.remove_layer(&eviction.digest, &layers[&eviction.digest].keys)?;
+        }
+        let evicted: Vec<&str> = report
+            .evicted
+            .iter()
+            .map(|eviction| eviction.digest.as_str())
+            .collect();
+        self.unindex_layers(&evicted);
+        for blob in unused_blobs {
+            self.storage.delete(blob)?;
+        }
+        Ok(report)
+    }
+
+    /// When the layer was last read (or built), according to `record_access()`, or when it was
+    /// built if it has never been used on this machine.
+    fn last_access(&self, digest: &str, description: Option<&StoredDescription>) -> SystemTime {
+        if let Ok(metadata) = std::fs::metadata(self.access_path(digest)) {
+            if let Ok(accessed) = metadata.modified() {
+                return accessed;
+            }
+        }
+        match description.map(|description| description.age()) {
+            Some(Ok(age)) => SystemTime::now() - age,
+            _ => SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    fn remove_layer(&self, digest: &str, keys: &[String]) -> Result<()> {
+        log::info!("removing {digest}");
+        self.remove_layer_keys(digest, keys, |key| self.storage.delete(key))?;
+        let access_path = self.access_path(digest);
+        if access_path.exists() {
+            std::fs::remove_file(&access_path)
+                .with_context(|| format!("removing {access_path:?}"))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct Lockfile {
+    #[serde(default)]
+    package: Vec<LockedPackage>,
+}
+
+#[derive(Deserialize)]
+struct LockedPackage {
+    name: String,
+    version: String,
+}
+
+/// The layers that a build of any of `lockfiles` could use: layers of any package version that
+/// they mention (whatever its features or profile), and everything that those layers were built
+/// on top of.
+fn reachable_from_lockfiles<'a>(
+    lockfiles: &[PathBuf],
+    layers: &'a BTreeMap<String, Layer>,
+) -> Result<BTreeSet<&'a str>> {
+    let mut packages = BTreeSet::new();
+    for path in lockfiles {
+        let contents =
+            std::fs::read_to_string(path).with_context(|| format!("reading {path:?}"))?;
+        let lockfile: Lockfile =
+            toml::from_str(&contents).with_context(|| format!("parsing {path:?}"))?;
+        packages.extend(
+            lockfile
+                .package
+                .into_iter()
+                .map(|package| (package.name, package.version)),
+        );
+    }
+
+    let mut reachable = BTreeSet::new();
+    let mut queue: Vec<&str> = layers
+        .iter()
+        .filter(|(_, layer)| match &layer.description {
+            Some(description) => {
+                packages.contains(&(description.name.clone(), description.version.clone()))
+            }
+            None => false,
+        })
+        .map(|(digest, _)| digest.as_str())
+        .collect();
+    while let Some(digest) = queue.pop() {
+        if !reachable.insert(digest) {
+            continue;
+        }
+        if let Some(description) = layers
+            .get(digest)
+            .and_then(|layer| layer.description.as_ref())
+        {
+            for dep in &description.deps {
+                if let Some((dep, _)) = layers.get_key_value(dep) {
+                    queue.push(dep);
+                }
+            }
+        }
+    }
+    Ok(reachable)
+}
+
+#[cfg(test)]
+mod tests {
+    use filetime::FileTime;
+
+    use super::*;
+    use crate::repo::tests::{insert_layer, test_repo};
+    use crate::storage::Storage;
+    use crate::util::hash::sha256_reader;
+
+    #[test]
+    fn evicts_least_recently_used_layers_and_their_blobs() -> Result<()> {
+        let (_local_dir, storage, repo) = test_repo();
+        insert_layer(&repo, &storage, "old", &[b"shared", b"old"]);
+        insert_layer(&repo, &storage, "new", &[b"shared", b"new"]);
+        repo.write_blob(&sha256_reader(&b"unused"[..])?, &b"unused"[..])?;
+        repo.record_access("old");
+        repo.record_access("new");
+        filetime::set_file_mtime(repo.access_path("old"), FileTime::from_unix_time(0, 0))?;
+
+        let total = storage
+            .list()?
+            .iter()
+            .map(|key| storage.size(key))
+            .sum::<Result<u64>>()?;
+        let evicted = |report: &GcReport| -> Vec<String> {
+            report
+                .evicted
+                .iter()
+                .map(|eviction| eviction.digest.clone())
+                .collect()
+        };
+
+        // Removing the blob that no layer uses is enough to get under the limit.
+        let policy = GcPolicy {
+            max_size: Some(total - 1),
+            ..GcPolicy::default()
+        };
+        let report = repo.gc(&policy, false)?;
+        assert_eq!(evicted(&report), Vec::<String>::new());
+        assert_eq!(report.blobs_removed, 1);
+        assert_eq!(report.remaining, total - report.freed);
+        let keys = storage.list()?;
+        assert!(!keys.contains(&blob_key(&sha256_reader(&b"unused"[..])?)));
+
+        let policy = GcPolicy {
+            max_size: Some(report.remaining - 1),
+            ..GcPolicy::default()
+        };
+        let report = repo.gc(&policy, false)?;
+        assert_eq!(evicted(&report), vec!["old"]);
+        assert_eq!(report.blobs_removed, 1);
+
+        let keys = storage.list()?;
+        assert!(!keys.iter().any(|key| key.starts_with("old.")), "{keys:?}");
+        assert!(keys.contains(&blob_key(&sha256_reader(&b"shared"[..])?)));
+        assert!(!repo.access_path("old").exists());
+        assert!(repo.verify(false)?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,321 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+
+use super::Repo;
+use crate::description::StoredDescription;
+use crate::manifest::{filetime_as_string, EntryKind, Manifest};
+use crate::util::flock::Flock;
+use crate::util::hash::sha256_reader;
+
+/// A layer that contains a file, found by `Repo::find_file()`.
+#[derive(Debug)]
+pub struct FoundFile {
+    pub description: StoredDescription,
+    pub mtime: FileTime,
+    /// `None` for directories and symlinks.
+    pub size: Option<u64>,
+}
+
+/// One line of a shard of the index.
+#[derive(Serialize, Deserialize)]
+struct IndexEntry {
+    path: PathBuf,
+    digest: String,
+    #[serde(with = "filetime_as_string")]
+    mtime: FileTime,
+    size: Option<u64>,
+}
+
+/// Lines are sharded by the hash of their path, so that a lookup only reads 1/256th of the index.
+const SHARD_PREFIX_LEN: usize = 2;
+
+/// The index of which layers contain which paths, kept in `{local_dir}/index`, so that looking up
+/// a path doesn't mean reading every manifest in the repo, or even listing the repo.
+///
+/// `paths/{xx}.jsonl` has a line for each path in each indexed layer, and `layers` lists the
+/// layers that are in the index. `commit()` adds layers, and `repo gc` and `repo verify` remove
+/// them from `layers` (under `.lock`). A layer is only added to `layers` once all of its paths
+/// are written, so an interrupted update just gets redone. Lines for layers that aren't in
+/// `layers` are ignored, and dropped when the index is rebuilt.
+///
+/// Layers that other processes add or remove behind our back (e.g. a remote that another machine
+/// commits to) aren't noticed until the index is reconciled with storage, which happens when an
+/// indexed layer turns out to be missing, or on `repo find --rebuild-index`.
+impl Repo {
+    /// Returns every layer that contains `filename`, along with its mtime and size in that layer.
+    pub fn find_file(&self, filename: &Path) -> Result<Vec<FoundFile>> {
+        if !self.index_dir().join("layers").exists() {
+            self.reconcile_index()?;
+        }
+        if let Some(found) = self.lookup(filename, false)? {
+            return Ok(found);
+        }
+        // One of the layers that the index knows about has gone, so it is out of date.
+        self.reconcile_index()?;
+        Ok(self.lookup(filename, true)?.unwrap_or_default())
+    }
+
+    /// Returns `None` if one of the layers that contains `filename` is missing from the repo,
+    /// unless `skip_missing` is set.
+    fn lookup(&self, filename: &Path, skip_missing: bool) -> Result<Option<Vec<FoundFile>>> {
+        let layers = self.indexed_layers()?;
+        let shard = self.shard_path(filename)?;
+        let file = match File::open(&shard) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Some(Vec::new())),
+            Err(e) => return Err(e).with_context(|| format!("opening {shard:?}")),
+        };
+        let mut seen = BTreeSet::new();
+        let mut found = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.with_context(|| format!("reading {shard:?}"))?;
+            let entry: IndexEntry = serde_json::from_str(&line).with_context(|| {
+                format!("parsing {shard:?} (`repo find --rebuild-index` will rebuild it)")
+            })?;
+            if entry.path != filename
+                || !layers.contains(&entry.digest)
+                || !seen.insert(entry.digest.clone())
+            {
+                continue;
+            }
+            let manifest_key = format!("{}.manifest.json", entry.digest);
+            let description = if self.storage.has(&manifest_key)? {
+                self.description(&entry.digest)?
+            } else {
+                None
+            };
+            match description {
+                Some(description) => found.push(FoundFile {
+                    description,
+                    mtime: entry.mtime,
+                    size: entry.size,
+                }),
+                None if skip_missing => {}
+                None => return Ok(None),
+            }
+        }
+        found.sort_by(|a, b| a.description.digest.cmp(&b.description.digest));
+        Ok(Some(found))
+    }
+
+    /// Throw the index away and index every layer in the repo again.
+    pub fn rebuild_index(&self) -> Result<()> {
+        let index_dir = self.index_dir();
+        {
+            let _lock = self.lock_index()?;
+            for name in ["layers", "paths"] {
+                let path = index_dir.join(name);
+                let result = if path.is_dir() {
+                    std::fs::remove_dir_all(&path)
+                } else {
+                    std::fs::remove_file(&path)
+                };
+                match result {
+                    Err(e) if e.kind() != ErrorKind::NotFound => {
+                        return Err(e).with_context(|| format!("removing {path:?}"))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        self.reconcile_index()
+    }
+
+    /// Add a newly-committed layer to the index. The index is only a cache, so this only warns
+    /// if it fails: the layer will be indexed when the index is next reconciled.
+    pub(super) fn index_layer(&self, digest: &str, manifest: &Manifest) {
+        let result = self
+            .lock_index()
+            .and_then(|_lock| self.write_index_entries(digest, manifest));
+        if let Err(e) = result {
+            log::warn!("failed to add {digest} to the index: {e:#}");
+        }
+    }
+
+    /// Remove layers that are being removed from the repo from the index. Like `index_layer()`,
+    /// this only warns if it fails: `find_file()` notices missing layers by itself.
+    pub(super) fn unindex_layers(&self, digests: &[&str]) {
+        let result = self.lock_index().and_then(|_lock| {
+            let mut layers = self.indexed_layers()?;
+            for digest in digests {
+                layers.remove(*digest);
+            }
+            self.write_indexed_layers(&layers)
+        });
+        if let Err(e) = result {
+            log::warn!("failed to remove {digests:?} from the index: {e:#}");
+        }
+    }
+
+    /// Bring the index up to date with the layers in storage: index any layers that aren't in it
+    /// yet (e.g. ones that were built before the index existed, or that were committed to remote
+    /// storage by another machine), and forget about ones that have been removed.
+    fn reconcile_index(&self) -> Result<()> {
+        let layers: BTreeSet<String> = self
+            .storage
+            .list()?
+            .into_iter()
+            .filter_map(|key| key.strip_suffix(".manifest.json").map(str::to_owned))
+            .collect();
+
+        let _lock = self.lock_index()?;
+        let indexed = self.indexed_layers()?;
+        if indexed.iter().any(|digest| !layers.contains(digest)) {
+            self.write_indexed_layers(&indexed.intersection(&layers).cloned().collect())?;
+        }
+        let missing: Vec<&String> = layers.difference(&indexed).collect();
+        log::info!("adding {} layers to the index", missing.len());
+        for digest in missing {
+            let manifest = self.manifest(digest).with_context(|| {
+                format!("indexing {digest} (`repo verify --quarantine` will remove broken layers)")
+            })?;
+            if let Some(manifest) = manifest {
+                self.write_index_entries(digest, &manifest)?;
+            }
+        }
+        // Make sure that `layers` exists, even if the repo is empty.
+        append(&self.index_dir().join("layers"), "")
+    }
+
+    /// Must be called with the index locked.
+    fn write_index_entries(&self, digest: &str, manifest: &Manifest) -> Result<()> {
+        let mut shards: BTreeMap<PathBuf, String> = BTreeMap::new();
+        for (path, entry) in &manifest.entries {
+            let line = serde_json::to_string(&IndexEntry {
+                path: path.clone(),
+                digest: digest.to_owned(),
+                mtime: entry.mtime,
+                size: match entry.kind {
+                    EntryKind::File { size, .. } => Some(size),
+                    _ => None,
+                },
+            })?;
+            let shard = shards.entry(self.shard_path(path)?).or_default();
+            shard.push_str(&line);
+            shard.push('\n');
+        }
+        let paths_dir = self.index_dir().join("paths");
+        std::fs::create_dir_all(&paths_dir).with_context(|| format!("creating {paths_dir:?}"))?;
+        for (shard, lines) in shards {
+            append(&shard, &lines)?;
+        }
+        append(&self.index_dir().join("layers"), &format!("{digest}\n"))
+    }
+
+    fn indexed_layers(&self) -> Result<BTreeSet<String>> {
+        let path = self.index_dir().join("layers");
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => Ok(contents.lines().map(str::to_owned).collect()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(BTreeSet::new()),
+            Err(e) => Err(e).with_context(|| format!("reading {path:?}")),
+        }
+    }
+
+    /// Must be called with the index locked. Writes to a temporary file and renames it into
+    /// place, so that readers (who don't take the lock) never see a partial list.
+    fn write_indexed_layers(&self, layers: &BTreeSet<String>) -> Result<()> {
+        let path = self.index_dir().join("layers");
+        let tmp = self.index_dir().join("layers.tmp");
+        let contents: String = layers.iter().map(|digest| format!("{digest}\n")).collect();
+        std::fs::write(&tmp, contents).with_context(|| format!("writing {tmp:?}"))?;
+        std::fs::rename(&tmp, &path).with_context(|| format!("renaming {tmp:?} to {path:?}"))
+    }
+
+    fn shard_path(&self, path: &Path) -> Result<PathBuf> {
+        let hash = sha256_reader(path.to_string_lossy().as_bytes())?;
+        Ok(self
+            .index_dir()
+            .join("paths")
+            .join(format!("{}.jsonl", &hash[..SHARD_PREFIX_LEN])))
+    }
+
+    fn lock_index(&self) -> Result<Flock> {
+        let index_dir = self.index_dir();
+        std::fs::create_dir_all(&index_dir).with_context(|| format!("creating {index_dir:?}"))?;
+        Flock::exclusive(&index_dir.join(".lock"))
+    }
+
+    fn index_dir(&self) -> PathBuf {
+        self.local_dir.join("index")
+    }
+}
+
+fn append(path: &Path, contents: &str) -> Result<()> {
+    File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .with_context(|| format!("appending to {path:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::repo::tests::{insert_layer, test_repo};
+    use crate::storage::Storage;
+
+    #[test]
+    fn finds_files_in_new_and_removed_layers() -> Result<()> {
+        let (_local_dir, storage, repo) = test_repo();
+        let description = |digest: &str| {
+            serde_json::json!({
+                "digest": digest,
+                "name": digest,
+                "version": "1.0.0",
+                "source": "",
+                "build_for": "target",
+                "profile": "dev",
+                "features": [],
+                "cargo_toml": "",
+                "cargo_config_toml": "",
+                "toolchain": {
+                    "rustc_version": "",
+                    "rustc_commit_hash": "",
+                    "host": "",
+                    "target": "",
+                    "cargo_version": "",
+                },
+                "created_at": "2022-05-01T00:00:00Z",
+                "deps": [],
+            })
+            .to_string()
+        };
+        let digests = |found: Vec<FoundFile>| -> Vec<String> {
+            found
+                .into_iter()
+                .map(|found| found.description.digest)
+                .collect()
+        };
+        let path = Path::new("target/debug/file-0");
+
+        insert_layer(&repo, &storage, "a", &[b"a"]);
+        storage.insert("a.description.json", description("a").as_bytes());
+        assert_eq!(digests(repo.find_file(path)?), vec!["a"]);
+
+        // Layers that bypass `commit()` aren't noticed until the index is reconciled.
+        insert_layer(&repo, &storage, "b", &[b"b"]);
+        storage.insert("b.description.json", description("b").as_bytes());
+        assert_eq!(digests(repo.find_file(path)?), vec!["a"]);
+        repo.index_layer("b", &repo.manifest("b")?.unwrap());
+        let found = repo.find_file(path)?;
+        assert_eq!(found[1].size, Some(1));
+        assert_eq!(digests(found), vec!["a", "b"]);
+        assert!(repo.find_file(Path::new("target/debug/file-1"))?.is_empty());
+
+        storage.delete("a.manifest.json")?;
+        assert_eq!(digests(repo.find_file(path)?), vec!["b"]);
+        repo.unindex_layers(&["b"]);
+        assert!(repo.find_file(path)?.is_empty());
+        repo.rebuild_index()?;
+        assert_eq!(digests(repo.find_file(path)?), vec!["b"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,562 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use crypto_hash::{Algorithm, Hasher};
+use filetime::FileTime;
+use serde::de::DeserializeOwned;
+use tempdir::TempDir;
+
+use crate::{
+    archive::{materialise, UnpackMode},
+    compression::Compression,
+    description::{PackageDescription, StoredDescription},
+    manifest::Manifest,
+    remote,
+    stats::{ComputedStats, Stats},
+    storage::{LocalStorage, Storage, Tiered},
+    util::{
+        flock::Flock,
+        hash::{hex, sha256_reader},
+    },
+};
+
+pub mod gc;
+mod index;
+mod verify;
+
+/// Prebuilt layers, kept in some `Storage`.
+///
+/// Each layer is a `{digest}.manifest.json`, with `{digest}.description.json`,
+/// `{digest}.toolchain.json`, `{digest}.stats.json` and `{digest}.manifest.json.sha256` sidecars.
+/// The manifest refers to the contents of each file by hash, and the contents are stored once as
+/// `{sha256}.blob`, however many layers contain them. Manifests and blobs are checked against
+/// their hashes whenever they are read.
+///
+/// Some things only ever live on this machine, in `local_dir`: build logs, locks on layers that
+/// are being built, when each layer was last used (for `repo gc`), an index of which layers
+/// contain which paths (for `find_file()`), and (depending on the `UnpackMode`) extracted copies
+/// of layers.
+pub struct Repo {
+    storage: Box<dyn Storage>,
+    local_dir: PathBuf,
+    compression: Compression,
+    unpack_mode: UnpackMode,
+    /// Keeps `repo gc` from running while we're using the repo, or vice versa.
+    _lock: Option<Flock>,
+}
+
+impl Repo {
+    pub fn new(
+        storage: Box<dyn Storage>,
+        local_dir: PathBuf,
+        compression: Compression,
+        unpack_mode: UnpackMode,
+    ) -> Self {
+        Self {
+            storage,
+            local_dir,
+            compression,
+            unpack_mode,
+            _lock: None,
+        }
+    }
+
+    /// Layers are stored in `CARGO_QUICK_TARBALL_DIR` (or `~/tmp/quick`). If a remote is
+    /// configured, then that directory acts as a read-through cache for it, and newly built layers
+    /// are uploaded to it. New layers are compressed with `CARGO_QUICK_COMPRESSION`, and layers
+    /// are unpacked with `CARGO_QUICK_UNPACK_MODE`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let tarball_dir = tarball_dir_from_env();
+        let local = Box::new(LocalStorage::new(tarball_dir.clone())?);
+        let lock = Flock::shared(&tarball_dir.join(".lock"))?;
+        let storage: Box<dyn Storage> = match remote::from_env() {
+            Some(remote) => Box::new(Tiered::new(vec![local, remote])),
+            None => local,
+        };
+        Ok(Self {
+            _lock: Some(lock),
+            ..Self::new(
+                storage,
+                tarball_dir,
+                Compression::from_env()?,
+                UnpackMode::from_env()?,
+            )
+        })
+    }
+
+    /// Just the local part of the repo from `from_env()`, for looking at what this machine has
+    /// without downloading anything from the remote.
+    pub fn local_from_env() -> anyhow::Result<Self> {
+        let tarball_dir = tarball_dir_from_env();
+        let local = Box::new(LocalStorage::new(tarball_dir.clone())?);
+        let lock = Flock::shared(&tarball_dir.join(".lock"))?;
+        Ok(Self {
+            _lock: Some(lock),
+            ..Self::new(
+                local,
+                tarball_dir,
+                Compression::from_env()?,
+                UnpackMode::from_env()?,
+            )
+        })
+    }
+
+    /// Just the local part of the repo from `from_env()`, with nobody else using it. This is for
+    /// maintenance that would break concurrent builds, like `repo gc` deleting blobs that a build
+    /// has written but not committed a manifest for yet.
+    ///
+    /// Every other cargo-quickbuild process holds a shared lock on the repo for as long as it
+    /// runs, so this fails rather than waiting for all of them to finish.
+    pub fn exclusive_local_from_env() -> anyhow::Result<Self> {
+        let tarball_dir = tarball_dir_from_env();
+        let local = Box::new(LocalStorage::new(tarball_dir.clone())?);
+        let lock = Flock::try_exclusive(&tarball_dir.join(".lock"))?.with_context(|| {
+            format!(
+                "{tarball_dir:?} is being used by other cargo-quickbuild processes (e.g. running \
+                builds). Try again once they have finished."
+            )
+        })?;
+        Ok(Self {
+            _lock: Some(lock),
+            ..Self::new(
+                local,
+                tarball_dir,
+                Compression::from_env()?,
+                UnpackMode::from_env()?,
+            )
+        })
+    }
+
+    pub fn unpack_mode(&self) -> UnpackMode {
+        self.unpack_mode
+    }
+
+    /// Lock a layer for building, waiting for any other process on this machine that is already
+    /// building it. Once the lock is held, check `has()` again: if it is now true, someone else
+    /// built the layer while we were waiting.
+    ///
+    /// This doesn't stop two machines that share remote storage from building the same layer at
+    /// once, but `commit()` won't overwrite a layer that is already there.
+    pub fn lock_layer(&self, package: &PackageDescription) -> anyhow::Result<Flock> {
+        let locks_dir = self.local_dir.join("locks");
+        std::fs::create_dir_all(&locks_dir).with_context(|| format!("creating {locks_dir:?}"))?;
+        let path = locks_dir.join(format!("{}.lock", package.pretty_digest()));
+        if let Some(lock) = Flock::try_exclusive(&path)? {
+            return Ok(lock);
+        }
+        println!(
+            "waiting for another process to finish building {:?}",
+            package.pretty_digest()
+        );
+        Flock::exclusive(&path)
+    }
+
+    pub fn has(&self, package: &PackageDescription) -> bool {
+        let key = key(package, "manifest.json");
+        match self.storage.has(&key) {
+            Ok(found) => found,
+            Err(e) => {
+                log::warn!("treating {key} as missing: {e:#}");
+                false
+            }
+        }
+    }
+
+    /// The layer's manifest. There is no need to check which toolchain it was built with,
+    /// because that is part of the digest (`repo verify` checks that the sidecars agree).
+    pub fn read(&self, package: &PackageDescription) -> anyhow::Result<Manifest> {
+        let manifest_key = key(package, "manifest.json");
+        let manifest = self
+            .read_manifest(&manifest_key)?
+            .with_context(|| format!("{manifest_key} is missing"))?;
+        self.record_access(&package.pretty_digest());
+        Ok(manifest)
+    }
+
+    /// Note that a layer has just been used, so that `repo gc` evicts it last. This is only
+    /// tracked locally, in `{local_dir}/access/{digest}`.
+    fn record_access(&self, digest: &str) {
+        let path = self.access_path(digest);
+        let result = std::fs::create_dir_all(path.parent().unwrap())
+            .and_then(|()| File::create(&path))
+            .and_then(|_| filetime::set_file_mtime(&path, FileTime::now()));
+        if let Err(e) = result {
+            log::warn!("failed to record access to {digest} in {path:?}: {e}");
+        }
+    }
+
+    fn access_path(&self, digest: &str) -> PathBuf {
+        self.local_dir.join("access").join(digest)
+    }
+
+    fn read_manifest(&self, key: &str) -> anyhow::Result<Option<Manifest>> {
+        let mut contents = Vec::new();
+        match self.storage.read(key)? {
+            Some(mut reader) => reader
+                .read_to_end(&mut contents)
+                .with_context(|| format!("reading {key}"))?,
+            None => return Ok(None),
+        };
+        let checksum_key = checksum_key(key);
+        let mut expected = String::new();
+        self.storage
+            .read(&checksum_key)?
+            .with_context(|| format!("{checksum_key} is missing"))?
+            .read_to_string(&mut expected)
+            .with_context(|| format!("reading {checksum_key}"))?;
+        check_sha256(key, expected.trim(), &contents)?;
+        Ok(Some(
+            serde_json::from_slice(&contents).with_context(|| format!("parsing {key}"))?,
+        ))
+    }
+
+    /// Returns a directory containing the layer's files, extracting them first if necessary, for
+    /// `materialise()` to link from. Returns `None` in `UnpackMode::Copy`, where we don't keep
+    /// extracted layers.
+    ///
+    /// Extracted layers contain paths in the stable scratch dir, like blobs do.
+    pub fn extract(
+        &self,
+        package: &PackageDescription,
+        manifest: &Manifest,
+    ) -> anyhow::Result<Option<PathBuf>> {
+        if self.unpack_mode == UnpackMode::Copy {
+            return Ok(None);
+        }
+        let extracted_dir = self.local_dir.join("extracted");
+        let dir = extracted_dir.join(package.pretty_digest());
+        if dir.exists() {
+            return Ok(Some(dir));
+        }
+        std::fs::create_dir_all(&extracted_dir)
+            .with_context(|| format!("creating {extracted_dir:?}"))?;
+        // Extract somewhere private and then rename it into place, so that nobody links from a
+        // half-extracted layer.
+        let tempdir = TempDir::new_in(&extracted_dir, &package.pretty_digest())?;
+        materialise(self, manifest, None, &tempdir.path().join("target"), None)
+            .with_context(|| format!("extracting {package:?}"))?;
+        match std::fs::rename(tempdir.path(), &dir) {
+            Ok(()) => {
+                // It's not ours to clean up any more.
+                tempdir.into_path();
+            }
+            // Someone else got there first.
+            Err(_) if dir.exists() => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("renaming {:?} to {dir:?}", tempdir.path()))
+            }
+        }
+        Ok(Some(dir))
+    }
+
+    /// Returns a reader for the (uncompressed) contents of a blob. The contents are checked
+    /// against `sha256` as they are read, and the reader fails at the end if they don't match.
+    pub fn read_blob(&self, sha256: &str) -> anyhow::Result<impl Read + Send> {
+        let key = blob_key(sha256);
+        let reader = self
+            .storage
+            .read(&key)?
+            .with_context(|| format!("{key} is missing"))?;
+        let decoder = Compression::decoder(reader).with_context(|| format!("reading {key}"))?;
+        Ok(CheckedReader {
+            inner: decoder,
+            hasher: Hasher::new(Algorithm::SHA256),
+            key,
+            expected: sha256.to_owned(),
+        })
+    }
+
+    /// Store `contents` as a blob, unless we already have it. Returns the number of bytes that
+    /// were stored.
+    ///
+    /// Blobs are committed straight away: they are only reachable through a manifest, which is
+    /// committed after all of its blobs.
+    pub fn write_blob(&self, sha256: &str, mut contents: impl Read) -> anyhow::Result<u64> {
+        let key = blob_key(sha256);
+        if self.storage.has(&key)? {
+            return Ok(0);
+        }
+        let mut encoder = self.compression.encoder(self.storage.write(&key)?)?;
+        std::io::copy(&mut contents, &mut encoder).with_context(|| format!("writing {key}"))?;
+        let staged = encoder.finish()?;
+        let stored_size = staged.len()?;
+        self.storage.commit(vec![staged])?;
+        Ok(stored_size)
+    }
+
+    pub fn write_stdout(&self, package: &PackageDescription) -> std::io::Result<File> {
+        self.write_log(package, "stdout")
+    }
+
+    pub fn write_stderr(&self, package: &PackageDescription) -> std::io::Result<File> {
+        self.write_log(package, "stderr")
+    }
+
+    /// The log of the final `cargo {subcommand}` that runs in the user's project, which lives
+    /// alongside the build logs of the layers, as `cargo-{subcommand}.{suffix}`.
+    pub fn write_cargo_log(&self, subcommand: &str, suffix: &str) -> std::io::Result<File> {
+        self.create_log(&format!("cargo-{subcommand}"), suffix)
+    }
+
+    fn write_log(&self, package: &PackageDescription, suffix: &str) -> std::io::Result<File> {
+        self.create_log(&package.pretty_digest(), suffix)
+    }
+
+    fn create_log(&self, name: &str, suffix: &str) -> std::io::Result<File> {
+        std::fs::create_dir_all(&self.local_dir)?;
+        let path = self.log_path(name, suffix);
+        File::options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+    }
+
+    /// Write the manifest and sidecars for a layer, and then make the whole layer visible. The
+    /// manifest goes last, so that anyone who can see it can also see its sidecars (and its
+    /// blobs, which `write_blob()` has already committed).
+    ///
+    /// A layer that is already in the repo is left alone, rather than having its sidecars
+    /// overwritten from under its manifest (e.g. its checksum, which would no longer match).
+    /// Callers should hold `lock_layer()`.
+    pub fn commit(
+        &self,
+        package: &PackageDescription,
+        deps: &[PackageDescription],
+        manifest: &Manifest,
+        stats: Stats,
+        stored_size: u64,
+    ) -> anyhow::Result<()> {
+        let manifest_key = key(package, "manifest.json");
+        if self.storage.has(&manifest_key)? {
+            println!("{manifest_key} was committed by someone else while we were building it");
+            return Ok(());
+        }
+
+        let mut toolchain = self.storage.write(&key(package, "toolchain.json"))?;
+        serde_json::to_writer_pretty(&mut toolchain, package.toolchain())?;
+        toolchain.flush()?;
+
+        let mut computed_stats = self.storage.write(&key(package, "stats.json"))?;
+        serde_json::to_writer_pretty(
+            &mut computed_stats,
+            &ComputedStats::new(stats, self.compression, stored_size),
+        )?;
+        computed_stats.flush()?;
+
+        let mut description = self.storage.write(&key(package, "description.json"))?;
+        serde_json::to_writer_pretty(&mut description, &StoredDescription::new(package, deps))?;
+        description.flush()?;
+
+        let manifest_contents = serde_json::to_vec(manifest)?;
+        let mut checksum = self.storage.write(&checksum_key(&manifest_key))?;
+        writeln!(checksum, "{}", sha256_reader(manifest_contents.as_slice())?)?;
+        let mut manifest_file = self.storage.write(&manifest_key)?;
+        manifest_file.write_all(&manifest_contents)?;
+
+        self.storage.commit(vec![
+            toolchain,
+            computed_stats,
+            description,
+            checksum,
+            manifest_file,
+        ])?;
+
+        self.record_access(&package.pretty_digest());
+        self.index_layer(&package.pretty_digest(), manifest);
+        println!("wrote {manifest_key}");
+
+        Ok(())
+    }
+
+    /// Get rid of a layer's manifest and the rest of its `keys` with `remove()`, and throw away
+    /// its extracted copy. The manifest goes first, so that nobody sees a layer without its
+    /// sidecars.
+    fn remove_layer_keys(
+        &self,
+        digest: &str,
+        keys: &[String],
+        remove: impl Fn(&str) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let manifest_key = format!("{digest}.manifest.json");
+        remove(&manifest_key)?;
+        for key in keys {
+            if key != &manifest_key {
+                remove(key)?;
+            }
+        }
+        let extracted = self.local_dir.join("extracted").join(digest);
+        if extracted.exists() {
+            std::fs::remove_dir_all(&extracted)
+                .with_context(|| format!("removing {extracted:?}"))?;
+        }
+        Ok(())
+    }
+
+    /// The description of every layer in the repo, sorted by digest.
+    pub fn descriptions(&self) -> anyhow::Result<Vec<StoredDescription>> {
+        let mut keys = self.storage.list()?;
+        keys.retain(|key| key.ends_with(".description.json"));
+        keys.sort();
+        let mut descriptions = Vec::new();
+        for key in keys {
+            if let Some(description) = self.read_json(&key)? {
+                descriptions.push(description);
+            }
+        }
+        Ok(descriptions)
+    }
+
+    /// The description of the layer with the given `pretty_digest()`, if it exists.
+    pub fn description(&self, digest: &str) -> anyhow::Result<Option<StoredDescription>> {
+        self.read_json(&format!("{digest}.description.json"))
+    }
+
+    /// The stats of the layer with the given `pretty_digest()`, if it exists.
+    pub fn stats(&self, digest: &str) -> anyhow::Result<Option<ComputedStats>> {
+        self.read_json(&format!("{digest}.stats.json"))
+    }
+
+    /// Where the build logs (`suffix` is `stdout` or `stderr`) for a layer are written. They
+    /// only exist if the layer was built on this machine.
+    pub fn log_path(&self, digest: &str, suffix: &str) -> PathBuf {
+        self.local_dir.join(format!("{digest}.{suffix}"))
+    }
+
+    /// The manifest of the layer with the given `pretty_digest()`, if it exists.
+    pub fn manifest(&self, digest: &str) -> anyhow::Result<Option<Manifest>> {
+        self.read_manifest(&format!("{digest}.manifest.json"))
+    }
+
+    fn read_json<T: DeserializeOwned>(&self, key: &str) -> anyhow::Result<Option<T>> {
+        match self.storage.read(key)? {
+            Some(reader) => Ok(Some(
+                serde_json::from_reader(reader).with_context(|| format!("parsing {key}"))?,
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+fn tarball_dir_from_env() -> PathBuf {
+    match std::env::var("CARGO_QUICK_TARBALL_DIR") {
+        Ok(path) => PathBuf::from(path),
+        _ => home::home_dir().unwrap().join("tmp/quick"),
+    }
+}
+
+fn key(package: &PackageDescription, suffix: &str) -> String {
+    format!("{}.{suffix}", package.pretty_digest())
+}
+
+fn blob_key(sha256: &str) -> String {
+    format!("{sha256}.blob")
+}
+
+fn checksum_key(key: &str) -> String {
+    format!("{key}.sha256")
+}
+
+fn check_sha256(key: &str, expected: &str, contents: &[u8]) -> anyhow::Result<()> {
+    let actual = sha256_reader(contents)?;
+    anyhow::ensure!(actual == expected, corrupt(key, &actual, expected));
+    Ok(())
+}
+
+fn corrupt(key: &str, actual: &str, expected: &str) -> String {
+    format!(
+        "{key} is corrupt: its sha256 is {actual}, but it should be {expected} \
+        (`cargo quickbuild repo verify --quarantine` will move it out of the way)"
+    )
+}
+
+/// Hashes a blob as it is streamed, and fails at the end if it doesn't match its key, so that
+/// big blobs don't need to be held in memory to be checked.
+struct CheckedReader<R> {
+    inner: R,
+    hasher: Hasher,
+    key: String,
+    expected: String,
+}
+
+impl<R: Read> Read for CheckedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.hasher.write_all(&buf[..n])?;
+        } else if !buf.is_empty() {
+            let actual = hex(&self.hasher.finish());
+            if actual != self.expected {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    corrupt(&self.key, &actual, &self.expected),
+                ));
+            }
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use filetime::FileTime;
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::manifest::{EntryKind, ManifestEntry};
+    use crate::storage::memory::MemoryStorage;
+
+    /// A repo with a `MemoryStorage` (which the tests can also poke at directly), and a
+    /// `local_dir` that lasts as long as the returned `TempDir`.
+    pub(crate) fn test_repo() -> (TempDir, MemoryStorage, Repo) {
+        let local_dir = TempDir::new("repo").unwrap();
+        let storage = MemoryStorage::default();
+        let repo = Repo::new(
+            Box::new(storage.clone()),
+            local_dir.path().to_owned(),
+            Compression::Zstd,
+            UnpackMode::Copy,
+        );
+        (local_dir, storage, repo)
+    }
+
+    /// Put a layer containing `files` straight into `storage`, bypassing `Repo::commit()` (which
+    /// needs a `PackageDescription`).
+    pub(crate) fn insert_layer(
+        repo: &Repo,
+        storage: &MemoryStorage,
+        digest: &str,
+        files: &[&[u8]],
+    ) {
+        let mut manifest = Manifest::default();
+        for (i, contents) in files.iter().enumerate() {
+            let sha256 = sha256_reader(*contents).unwrap();
+            repo.write_blob(&sha256, *contents).unwrap();
+            manifest.entries.insert(
+                format!("target/debug/file-{i}").into(),
+                ManifestEntry {
+                    kind: EntryKind::File {
+                        sha256,
+                        size: contents.len() as u64,
+                        remapped: false,
+                    },
+                    mtime: FileTime::zero(),
+                    mode: 0o644,
+                },
+            );
+        }
+        let manifest = serde_json::to_vec(&manifest).unwrap();
+        storage.insert(&format!("{digest}.description.json"), b"{}");
+        storage.insert(&format!("{digest}.toolchain.json"), b"{}");
+        storage.insert(&format!("{digest}.stats.json"), b"{}");
+        storage.insert(
+            &format!("{digest}.manifest.json.sha256"),
+            sha256_reader(manifest.as_slice()).unwrap().as_bytes(),
+        );
+        storage.insert(&format!("{digest}.manifest.json"), &manifest);
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{Context, Result};
+
+use super::{blob_key, Repo};
+use crate::manifest::EntryKind;
+use crate::toolchain::Toolchain;
+
+/// Something wrong with an object in the repo, found by `Repo::verify()`.
+#[derive(Debug)]
+pub struct Problem {
+    pub key: String,
+    pub description: String,
+}
+
+/// The sidecars that every layer should have, alongside `{digest}.manifest.json`.
+const SIDECARS: &[&str] = &[
+    "description.json",
+    "toolchain.json",
+    "stats.json",
+    "manifest.json.sha256",
+];
+
+impl Repo {
+    /// Check every layer in the repo: that its sidecars are all present (and agree about the
+    /// toolchain), that its manifest matches its checksum, and that every blob that it refers to
+    /// is present and uncorrupted. Also looks
+    /// for sidecars whose layer is missing, and for staged files that were abandoned by a process
+    /// that died.
+    ///
+    /// If `quarantine` is set, then broken layers (and broken blobs) are moved into
+    /// `{local_dir}/quarantine`, so that they will be rebuilt, and abandoned files are removed.
+    ///
+    /// This doesn't look for blobs that no layer refers to, because a build that is in progress
+    /// commits its blobs before its manifest.
+    pub fn verify(&self, quarantine: bool) -> Result<Vec<Problem>> {
+        let keys: BTreeSet<String> = self.storage.list()?.into_iter().collect();
+        let mut problems = Vec::new();
+        let mut blobs: BTreeMap<String, Result<(), String>> = BTreeMap::new();
+        let mut digests = BTreeSet::new();
+        let mut quarantined = Vec::new();
+
+        for key in &keys {
+            let digest = match key.strip_suffix(".manifest.json") {
+                Some(digest) => digest,
+                None => continue,
+            };
+            digests.insert(digest);
+            log::info!("verifying {key}");
+
+            let mut layer_problems = Vec::new();
+            for sidecar in SIDECARS {
+                let sidecar_key = format!("{digest}.{sidecar}");
+                if !keys.contains(&sidecar_key) {
+                    layer_problems.push(format!("{sidecar_key} is missing"));
+                }
+            }
+            layer_problems.extend(self.check_toolchain(digest));
+            match self.read_manifest(key) {
+                Ok(Some(manifest)) => {
+                    for (path, entry) in &manifest.entries {
+                        if let EntryKind::File { sha256, .. } = &entry.kind {
+                            let blob = blobs
+                                .entry(sha256.clone())
+                                .or_insert_with(|| self.check_blob(&keys, sha256));
+                            if let Err(e) = blob {
+                                layer_problems.push(format!("{path:?}: {e}"));
+                            }
+                        }
+                    }
+                }
+                // It was removed while we were looking at it.
+                Ok(None) => continue,
+                Err(e) => layer_problems.push(format!("{e:#}")),
+            }
+
+            if !layer_problems.is_empty() {
+                problems.push(Problem {
+                    key: key.clone(),
+                    description: layer_problems.join("\n"),
+                });
+                if quarantine {
+                    self.quarantine_layer(digest, &keys)?;
+                    quarantined.push(digest);
+                }
+            }
+        }
+
+        if !quarantined.is_empty() {
+            self.unindex_layers(&quarantined);
+        }
+
+        for (sha256, blob) in blobs {
+            let key = blob_key(&sha256);
+            if let Err(description) = blob {
+                if keys.contains(&key) && quarantine {
+                    self.quarantine(&key)?;
+                }
+                problems.push(Problem { key, description });
+            }
+        }
+
+        for key in &keys {
+            let orphaned = SIDECARS.iter().any(|sidecar| {
+                key.strip_suffix(&format!(".{sidecar}"))
+                    .map(|digest| !digests.contains(digest))
+                    .unwrap_or(false)
+            });
+            if orphaned {
+                problems.push(Problem {
+                    key: key.clone(),
+                    description: String::from("orphaned: its layer's manifest is missing"),
+                });
+                if quarantine {
+                    self.quarantine(key)?;
+                }
+            }
+        }
+
+        for name in self.storage.abandoned()? {
+            problems.push(Problem {
+                key: name.clone(),
+                description: String::from(
+                    "abandoned by a process that exited without committing it",
+                ),
+            });
+            if quarantine {
+                self.storage.delete(&name)?;
+            }
+        }
+
+        Ok(problems)
+    }
+
+    /// The toolchain is part of the digest, so the description and `toolchain.json` should always
+    /// agree. Sidecars that can't be read are reported elsewhere, or not at all.
+    fn check_toolchain(&self, digest: &str) -> Option<String> {
+        let description = self.description(digest).ok()??;
+        let toolchain: Toolchain = self.read_json(&format!("{digest}.toolchain.json")).ok()??;
+        (toolchain.fingerprint() != description.toolchain.fingerprint()).then(|| {
+            format!(
+                "{digest}.toolchain.json says `{}` but the description says `{}`",
+                toolchain.fingerprint(),
+                description.toolchain.fingerprint(),
+            )
+        })
+    }
+
+    fn check_blob(&self, keys: &BTreeSet<String>, sha256: &str) -> Result<(), String> {
+        let key = blob_key(sha256);
+        if !keys.contains(&key) {
+            return Err(format!("{key} is missing"));
+        }
+        self.read_blob(sha256)
+            .and_then(|mut blob| Ok(std::io::copy(&mut blob, &mut std::io::sink())?))
+            .map(drop)
+            .map_err(|e| format!("{e:#}"))
+    }
+
+    /// Quarantine a layer's manifest and sidecars, and throw away its extracted copy.
+    fn quarantine_layer(&self, digest: &str, keys: &BTreeSet<String>) -> Result<()> {
+        let sidecars: Vec<String> = SIDECARS
+            .iter()
+            .map(|sidecar| format!("{digest}.{sidecar}"))
+            .filter(|key| keys.contains(key))
+            .collect();
+        self.remove_layer_keys(digest, &sidecars, |key| self.quarantine(key))
+    }
+
+    /// Move an object out of the repo and into `{local_dir}/quarantine`, for later inspection.
+    fn quarantine(&self, key: &str) -> Result<()> {
+        let quarantine_dir = self.local_dir.join("quarantine");
+        std::fs::create_dir_all(&quarantine_dir)
+            .with_context(|| format!("creating {quarantine_dir:?}"))?;
+        let path = quarantine_dir.join(key);
+        match self.storage.read(key) {
+            Ok(Some(mut reader)) => {
+                let mut file =
+                    std::fs::File::create(&path).with_context(|| format!("creating {path:?}"))?;
+                if let Err(e) = std::io::copy(&mut reader, &mut file) {
+                    log::warn!("failed to copy {key} into quarantine: {e}");
+                }
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("failed to copy {key} into quarantine: {e:#}"),
+        }
+        self.storage.delete(key)?;
+        log::info!("quarantined {key} in {path:?}");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::compression::Compression;
+    use crate::repo::tests::{insert_layer, test_repo};
+    use crate::storage::Storage;
+    use crate::util::hash::sha256_reader;
+
+    #[test]
+    fn finds_and_quarantines_broken_layers() -> Result<()> {
+        let (local_dir, storage, repo) = test_repo();
+        insert_layer(&repo, &storage, "good", &[b"shared", b"good"]);
+        insert_layer(&repo, &storage, "bad", &[b"shared", b"bad"]);
+        storage.insert(&blob_key(&sha256_reader(&b"bad"[..])?), b"not zstd");
+        storage.insert("gone.stats.json", b"{}");
+        assert_eq!(repo.verify(false)?.len(), 3);
+
+        let problems: Vec<String> = repo
+            .verify(true)?
+            .into_iter()
+            .map(|problem| problem.key)
+            .collect();
+        assert_eq!(
+            problems,
+            vec![
+                "bad.manifest.json".to_owned(),
+                blob_key(&sha256_reader(&b"bad"[..])?),
+                "gone.stats.json".to_owned(),
+            ]
+        );
+        assert!(local_dir
+            .path()
+            .join("quarantine/bad.manifest.json")
+            .exists());
+        let keys = storage.list()?;
+        assert!(!keys.iter().any(|key| key.starts_with("bad.")), "{keys:?}");
+        assert!(keys.contains(&"good.manifest.json".to_owned()));
+        assert!(repo.verify(false)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn finds_blobs_with_the_wrong_contents() -> Result<()> {
+        let (_local_dir, storage, repo) = test_repo();
+        insert_layer(&repo, &storage, "swapped", &[b"right"]);
+        let key = blob_key(&sha256_reader(&b"right"[..])?);
+        let mut encoder = Compression::Zstd.encoder(Vec::new())?;
+        encoder.write_all(b"wrong")?;
+        storage.insert(&key, &encoder.finish()?);
+
+        let problems = repo.verify(false)?;
+        let blob = problems.iter().find(|problem| problem.key == key);
+        assert!(
+            blob.is_some_and(|problem| problem.description.contains("is corrupt")),
+            "{problems:?}"
+        );
+        Ok(())
+    }
+}
@@ -1,4 +1,6 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+use std::sync::{Condvar, Mutex};
 
 use anyhow::Result;
 use cargo::core::resolver::features::FeaturesFor;
@@ -9,93 +11,295 @@ use crate::description::PackageDescription;
 use crate::quick_resolve::{BuildFor, QuickResolve};
 use crate::repo::Repo;
 
+type Node = (PackageId, BuildFor);
+
+/// Everything that we need to know in order to build a single layer.
+///
+/// This is computed up front on the main thread, because `QuickResolve` borrows from cargo
+/// data structures that can't be shared between threads.
+struct Job {
+    description: PackageDescription,
+    deps: Vec<PackageDescription>,
+}
+
+/// A job that is waiting for its dependencies to be built.
+struct Pending<N, J> {
+    job: J,
+    outstanding_deps: BTreeSet<N>,
+}
+
+/// The state that the worker threads share.
+///
+/// This is generic over the nodes and jobs so that the scheduling can be tested without cargo.
+struct Queue<N, J> {
+    pending: BTreeMap<N, Pending<N, J>>,
+    done: BTreeSet<N>,
+    running: usize,
+    error: Option<anyhow::Error>,
+}
+
+impl<N, J> Default for Queue<N, J> {
+    fn default() -> Self {
+        Queue {
+            pending: BTreeMap::new(),
+            done: BTreeSet::new(),
+            running: 0,
+            error: None,
+        }
+    }
+}
+
+impl<N: Ord + Copy, J> Queue<N, J> {
+    /// Take a job whose dependencies have all been built, if there is one.
+    fn take_ready(&mut self) -> Option<(N, J)> {
+        let node = *self
+            .pending
+            .iter()
+            .find(|(_, pending)| pending.outstanding_deps.is_subset(&self.done))
+            .map(|(node, _)| node)?;
+        let pending = self.pending.remove(&node).unwrap();
+        self.running += 1;
+        Some((node, pending.job))
+    }
+}
+
+/// Build every missing layer that any of `root_packages` depend on, running up to `jobs` builds
+/// at once. Workspace members (including the roots themselves) don't get layers.
+///
+/// A layer can start building as soon as all of its own dependencies are in the repo. The builds
+/// share a jobserver, so the total number of rustc processes is also limited to `jobs`.
 pub fn build_missing_packages(
     resolve: &QuickResolve,
     repo: &Repo,
-    root_package: PackageId,
+    root_packages: &[PackageId],
+    jobs: u32,
 ) -> Result<(), anyhow::Error> {
     let build_for = BuildFor(FeaturesFor::NormalOrDev);
 
-    let mut packages_to_build = resolve.recursive_deps_including_self(root_package, build_for);
-
-    // dbg!(&root_package);
-    // dbg!(&packages_to_build);
-    assert!(packages_to_build.contains(&(root_package, build_for)));
-
-    let mut built_packages: BTreeSet<(PackageId, BuildFor)> = Default::default();
-
-    // FIXME: I think it might be better to switch this out for a simple depth-first traversal.
-    // Mostly because it would reduce my iteration time - fewer packages need to be built before
-    // uncovering "level 1" problems.
-    for level in 0..=100 {
-        println!("START OF LEVEL {level}");
-        let current_level;
-        (current_level, packages_to_build) =
-            packages_to_build
-                .iter()
-                .partition(|(package_id, build_for)| {
-                    outstanding_deps(resolve, &built_packages, *package_id, *build_for).is_empty()
-                });
-
-        dbg!(&current_level);
-
-        if current_level.is_empty() && !packages_to_build.is_empty() {
-            println!(
-                "We haven't compiled everything yet, but there is nothing left to do\n\npackages_to_build: {packages_to_build:#?}"
-            );
-            dbg!(&built_packages);
-            for (package_id, build_for) in packages_to_build {
-                dbg!((
-                    package_id,
-                    outstanding_deps(resolve, &built_packages, package_id, build_for)
-                ));
-            }
-            anyhow::bail!("current_level.is_empty() && !packages_to_build.is_empty()");
+    let mut packages_to_build = BTreeSet::new();
+    for root_package in root_packages {
+        let deps = resolve.recursive_deps_including_dev_deps(*root_package, build_for);
+        assert!(deps.contains(&(*root_package, build_for)));
+        packages_to_build.extend(deps);
+    }
+
+    let mut queue = Queue::default();
+    for (package_id, build_for) in packages_to_build {
+        if resolve.is_workspace_member(package_id) {
+            continue;
         }
-        for (package_id, build_for) in current_level.iter().copied() {
-            if package_id == root_package {
-                // I suspect that I will also need to gracefully skip workspace packages, or something, for mvp
-                assert!(packages_to_build.is_empty());
-                assert_eq!(current_level.len(), 1);
-                println!("🎉 We're done here 🎉");
-                return Ok(());
-            }
-            build_tarball_if_not_exists(resolve, repo, package_id, build_for)?;
-            built_packages.insert((package_id, build_for));
+        let description = PackageDescription::new(resolve, package_id, build_for);
+        if repo.has(&description) {
+            log::info!("{:?} already exists", description.pretty_digest());
+            queue.done.insert((package_id, build_for));
+            continue;
         }
+        let outstanding_deps = outstanding_deps(resolve, package_id, build_for);
+        let deps = outstanding_deps
+            .iter()
+            .map(|(dep, build_for)| PackageDescription::new(resolve, *dep, *build_for))
+            .collect();
+        let job = Job { description, deps };
+        queue.pending.insert(
+            (package_id, build_for),
+            Pending {
+                job,
+                outstanding_deps,
+            },
+        );
     }
 
+    let jobs = jobs.max(1) as usize;
+    println!(
+        "building {} missing layers, {jobs} at a time",
+        queue.pending.len()
+    );
+    let jobserver = jobserver::Client::new(jobs)?;
+    run_jobs(queue, jobs, |job: Job| {
+        // Take the layer's lock before a jobserver token, so that we don't hold a token while
+        // waiting for another process to build the same layer.
+        let _lock = repo.lock_layer(&job.description)?;
+        let package_digest = job.description.pretty_digest();
+        if repo.has(&job.description) {
+            println!("{package_digest:?} was built by another process");
+            return Ok(());
+        }
+        let _token = jobserver.acquire()?;
+        println!("STARTING BUILD\n{package_digest:?}");
+        build_tarball(repo, &job.description, &job.deps, &jobserver)
+    })?;
+    println!("🎉 We're done here 🎉");
+    Ok(())
+}
+
+/// Run every job in `queue` on `jobs` threads, starting each one once all of its dependencies
+/// are done. The first error stops any more jobs from starting.
+fn run_jobs<N, J>(
+    queue: Queue<N, J>,
+    jobs: usize,
+    build: impl Fn(J) -> Result<()> + Sync,
+) -> Result<()>
+where
+    N: Ord + Copy + Debug + Send,
+    J: Send,
+{
+    let queue = Mutex::new(queue);
+    let condvar = Condvar::new();
+
+    std::thread::scope(|s| {
+        for _ in 0..jobs {
+            let (queue, condvar, build) = (&queue, &condvar, &build);
+            s.spawn(move || worker(queue, condvar, build));
+        }
+    });
+
+    let queue = queue.into_inner().unwrap();
+    if let Some(error) = queue.error {
+        return Err(error);
+    }
+    if !queue.pending.is_empty() {
+        anyhow::bail!(
+            "We haven't compiled everything yet, but there is nothing left to do\n\npackages_to_build: {:#?}",
+            queue.pending.keys().collect::<Vec<_>>()
+        );
+    }
     Ok(())
 }
 
-pub fn outstanding_deps<'cfg, 'a>(
-    resolve: &QuickResolve<'cfg, 'a>,
-    built_packages: &BTreeSet<(PackageId, BuildFor)>,
+fn worker<N: Ord + Copy + Debug, J>(
+    queue: &Mutex<Queue<N, J>>,
+    condvar: &Condvar,
+    build: &impl Fn(J) -> Result<()>,
+) {
+    loop {
+        let (node, job) = {
+            let mut queue = queue.lock().unwrap();
+            loop {
+                if queue.error.is_some() || queue.pending.is_empty() {
+                    return;
+                }
+                if let Some(ready) = queue.take_ready() {
+                    break ready;
+                }
+                if queue.running == 0 {
+                    // Nothing is running, so nothing will ever become ready. Leave the leftovers
+                    // in `pending` for build_missing_packages() to report.
+                    condvar.notify_all();
+                    return;
+                }
+                queue = condvar.wait(queue).unwrap();
+            }
+        };
+
+        let result = build(job);
+
+        let mut queue = queue.lock().unwrap();
+        queue.running -= 1;
+        match result {
+            Ok(()) => {
+                queue.done.insert(node);
+            }
+            Err(error) => {
+                queue
+                    .error
+                    .get_or_insert(error.context(format!("building {node:?}")));
+            }
+        }
+        condvar.notify_all();
+    }
+}
+
+/// All of the (recursive) dependencies of a package, which need to be built before it can be.
+fn outstanding_deps(
+    resolve: &QuickResolve,
     package_id: PackageId,
     build_for: BuildFor,
-) -> Vec<(PackageId, BuildFor)> {
+) -> BTreeSet<Node> {
     resolve
         .recursive_deps_including_self(package_id, build_for)
         .into_iter()
         // TODO: check that a package can't be a build-dep for itself
-        .filter(|(dep, b_for)| dep != &package_id && !built_packages.contains(&(*dep, *b_for)))
+        .filter(|(dep, _)| dep != &package_id)
         .collect()
 }
 
-pub fn build_tarball_if_not_exists<'cfg, 'a>(
-    resolve: &QuickResolve<'cfg, 'a>,
-    repo: &Repo,
-    package_id: PackageId,
-    build_for: BuildFor,
-) -> Result<()> {
-    let description = PackageDescription::new(resolve, package_id, build_for);
-    let package_digest = description.pretty_digest();
-
-    if repo.has(&description) {
-        let cargo_toml_deps = description.cargo_toml_deps();
-        println!("{package_digest:?} already exists (\n```\n{cargo_toml_deps}\n```\n)");
-        return Ok(());
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// A queue where each job is just the name of its node.
+    fn queue(graph: &[(&'static str, &[&'static str])]) -> Queue<&'static str, &'static str> {
+        let mut queue = Queue::default();
+        for (node, deps) in graph {
+            let pending = Pending {
+                job: *node,
+                outstanding_deps: deps.iter().copied().collect(),
+            };
+            queue.pending.insert(*node, pending);
+        }
+        queue
+    }
+
+    #[test]
+    fn deps_are_built_before_their_dependents() -> Result<()> {
+        let graph: &[(&str, &[&str])] = &[
+            ("app", &["lib", "macro"]),
+            ("lib", &["core"]),
+            ("macro", &["core"]),
+            ("core", &[]),
+        ];
+        let finished = Mutex::new(vec![]);
+        run_jobs(queue(graph), 4, |node| {
+            let (_, deps) = graph.iter().find(|(name, _)| *name == node).unwrap();
+            let mut finished = finished.lock().unwrap();
+            for dep in *deps {
+                anyhow::ensure!(finished.contains(dep), "{node} started before {dep}");
+            }
+            finished.push(node);
+            Ok(())
+        })?;
+        assert_eq!(finished.into_inner().unwrap().len(), graph.len());
+        Ok(())
+    }
+
+    #[test]
+    fn independent_jobs_run_concurrently() -> Result<()> {
+        let graph: &[(&str, &[&str])] = &[("a", &[]), ("b", &[])];
+        let started = Mutex::new(0);
+        let condvar = Condvar::new();
+        run_jobs(queue(graph), 2, |node| {
+            *started.lock().unwrap() += 1;
+            condvar.notify_all();
+            // Each job waits for the other one to start, which can only happen if both are
+            // running at once.
+            let (_started, timeout) = condvar
+                .wait_timeout_while(started.lock().unwrap(), Duration::from_secs(10), |n| *n < 2)
+                .unwrap();
+            anyhow::ensure!(!timeout.timed_out(), "{node} ran on its own");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn a_failed_job_stops_the_run() {
+        let graph: &[(&str, &[&str])] = &[("a", &[]), ("b", &["a"]), ("c", &["b"])];
+        let built = Mutex::new(vec![]);
+        let error = run_jobs(queue(graph), 2, |node| {
+            built.lock().unwrap().push(node);
+            anyhow::ensure!(node != "a", "{node} failed");
+            Ok(())
+        })
+        .unwrap_err();
+        assert_eq!(format!("{error:#}"), r#"building "a": a failed"#);
+        assert_eq!(built.into_inner().unwrap(), ["a"]);
+    }
+
+    #[test]
+    fn unsatisfiable_deps_are_reported() {
+        let graph: &[(&str, &[&str])] = &[("a", &[]), ("b", &["missing"])];
+        let error = run_jobs(queue(graph), 2, |_| Ok(())).unwrap_err();
+        assert!(error.to_string().contains("nothing left to do"), "{error}");
     }
-    println!("STARTING BUILD\n{package_digest:?}");
-    build_tarball(resolve, repo, package_id, build_for)
 }
@@ -2,6 +2,8 @@ use std::time::{Duration, Instant};
 
 use serde::{Deserialize, Serialize};
 
+use crate::compression::Compression;
+
 pub struct Stats {
     start: Instant,
     init_done: Option<Instant>,
@@ -65,22 +67,30 @@ mod duration_as_float_seconds {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ComputedStats {
     #[serde(with = "duration_as_float_seconds")]
-    init_duration: Duration,
+    pub init_duration: Duration,
     #[serde(with = "duration_as_float_seconds")]
-    untar_duration: Duration,
+    pub untar_duration: Duration,
     #[serde(with = "duration_as_float_seconds")]
-    build_duration: Duration,
+    pub build_duration: Duration,
     #[serde(with = "duration_as_float_seconds")]
-    tar_duration: Duration,
+    pub tar_duration: Duration,
+    #[serde(default)]
+    pub compression: Compression,
+    /// The size of the blobs that this layer added to the repo, after compression. Blobs that
+    /// were already there (e.g. identical files from other layers) don't count.
+    #[serde(default)]
+    pub stored_size: u64,
 }
 
-impl From<Stats> for ComputedStats {
-    fn from(stats: Stats) -> Self {
+impl ComputedStats {
+    pub fn new(stats: Stats, compression: Compression, stored_size: u64) -> Self {
         Self {
             init_duration: stats.init_duration(),
             untar_duration: stats.untar_duration(),
             build_duration: stats.build_duration(),
             tar_duration: stats.tar_duration(),
+            compression,
+            stored_size,
         }
     }
 }
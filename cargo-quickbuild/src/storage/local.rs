@@ -0,0 +1,112 @@
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+
+use super::{Staged, Storage};
+
+/// Objects are plain files in a directory. Staged files live alongside them, and are committed
+/// by renaming.
+#[derive(Debug)]
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&dir).with_context(|| format!("creating {dir:?}"))?;
+        Ok(LocalStorage { dir })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+}
+
+impl Storage for LocalStorage {
+    fn has(&self, key: &str) -> Result<bool> {
+        Ok(self.path(key).exists())
+    }
+
+    fn read(&self, key: &str) -> Result<Option<Box<dyn Read + Send>>> {
+        let path = self.path(key);
+        match File::open(&path) {
+            Ok(file) => Ok(Some(Box::new(file))),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("opening {path:?}")),
+        }
+    }
+
+    fn write(&self, key: &str) -> Result<Staged> {
+        Staged::new(&self.dir, key)
+    }
+
+    fn commit(&self, staged: Vec<Staged>) -> Result<()> {
+        for staged in staged {
+            let path = self.path(staged.key());
+            let temp_path = staged.finish()?;
+            std::fs::rename(&temp_path, &path)
+                .with_context(|| format!("renaming {temp_path:?} to {path:?}"))?;
+        }
+        Ok(())
+    }
+
+    fn abandoned(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if let Some(pid) = staged_by(&name) {
+                if !process_is_alive(pid) {
+                    names.push(name);
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // Dotfiles are ours (e.g. locks), not objects.
+            if entry.file_type()?.is_file() && !name.ends_with(".temp") && !name.starts_with('.') {
+                keys.push(name);
+            }
+        }
+        Ok(keys)
+    }
+
+    fn size(&self, key: &str) -> Result<u64> {
+        let path = self.path(key);
+        Ok(std::fs::metadata(&path)
+            .with_context(|| format!("checking size of {path:?}"))?
+            .len())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let path = self.path(key);
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("removing {path:?}"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The pid of the process that staged `name`, if it's a staged file (`{key}.{pid}-{n}.temp`, see
+/// `Staged::new()`).
+fn staged_by(name: &str) -> Option<libc::pid_t> {
+    let (_, suffix) = name.strip_suffix(".temp")?.rsplit_once('.')?;
+    let (pid, _) = suffix.split_once('-')?;
+    pid.parse().ok()
+}
+
+fn process_is_alive(pid: libc::pid_t) -> bool {
+    // SAFETY: signal 0 only checks whether we could send a signal.
+    let signalled = unsafe { libc::kill(pid, 0) } == 0;
+    // EPERM means that it exists, but belongs to someone else.
+    signalled || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
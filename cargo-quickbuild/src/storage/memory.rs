@@ -0,0 +1,69 @@
+use std::collections::BTreeMap;
+use std::io::{Cursor, Read};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+
+use super::{Staged, Storage};
+
+/// Objects are kept in memory, for tests. Clones share the same objects.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStorage {
+    objects: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+}
+
+impl MemoryStorage {
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.objects.lock().unwrap().get(key).cloned()
+    }
+
+    pub fn insert(&self, key: &str, contents: &[u8]) {
+        self.objects
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), contents.to_owned());
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn has(&self, key: &str) -> Result<bool> {
+        Ok(self.objects.lock().unwrap().contains_key(key))
+    }
+
+    fn read(&self, key: &str) -> Result<Option<Box<dyn Read + Send>>> {
+        Ok(self
+            .get(key)
+            .map(|contents| Box::new(Cursor::new(contents)) as _))
+    }
+
+    fn write(&self, key: &str) -> Result<Staged> {
+        Staged::new(&std::env::temp_dir(), key)
+    }
+
+    fn commit(&self, staged: Vec<Staged>) -> Result<()> {
+        for staged in staged {
+            let key = staged.key().to_owned();
+            let path = staged.finish()?;
+            let contents = std::fs::read(&path)?;
+            std::fs::remove_file(&path)?;
+            self.insert(&key, &contents);
+        }
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        Ok(self.objects.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn size(&self, key: &str) -> Result<u64> {
+        match self.get(key) {
+            Some(contents) => Ok(contents.len() as u64),
+            None => anyhow::bail!("{key} doesn't exist"),
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.objects.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
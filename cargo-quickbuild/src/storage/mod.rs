@@ -0,0 +1,119 @@
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{Context, Result};
+
+pub mod local;
+#[cfg(test)]
+pub mod memory;
+pub mod tiered;
+
+pub use local::LocalStorage;
+pub use tiered::Tiered;
+
+/// Somewhere to keep layers, as a flat namespace of objects keyed by file name (e.g.
+/// `{pretty_digest}.tar`).
+///
+/// Writes are staged, and only become visible when they are committed, so readers never see
+/// partially-written objects.
+pub trait Storage: Debug + Send + Sync {
+    /// Returns whether the key exists.
+    fn has(&self, key: &str) -> Result<bool>;
+    /// Open the key for reading, or return `None` if it doesn't exist.
+    fn read(&self, key: &str) -> Result<Option<Box<dyn Read + Send>>>;
+    /// Start writing the key. Nothing is visible until it is passed to `commit()`.
+    fn write(&self, key: &str) -> Result<Staged>;
+    /// Make staged objects visible, in order.
+    fn commit(&self, staged: Vec<Staged>) -> Result<()>;
+    /// All keys, in no particular order.
+    fn list(&self) -> Result<Vec<String>>;
+    /// The size of the object, in bytes. Only local storages need to support this (for `repo gc`).
+    fn size(&self, key: &str) -> Result<u64> {
+        anyhow::bail!("{self:?} can't tell the size of {key}")
+    }
+    /// Remove the key. Removing something that doesn't exist is not an error.
+    fn delete(&self, key: &str) -> Result<()>;
+    /// Staged objects whose writer went away without committing or removing them (e.g. because
+    /// it crashed). These aren't in `list()`, but can be passed to `delete()`.
+    fn abandoned(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+}
+
+/// An object that has been written to a temporary file, but not committed yet. The file is
+/// removed if this is dropped without being committed.
+#[derive(Debug)]
+pub struct Staged {
+    key: String,
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl Staged {
+    /// Stage `key` in a uniquely-named file in `dir`, so that concurrent writers of the same key
+    /// don't trample each other.
+    pub fn new(dir: &Path, key: &str) -> Result<Self> {
+        static STAGED: AtomicUsize = AtomicUsize::new(0);
+        let n = STAGED.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("{key}.{}-{n}.temp", std::process::id()));
+        let file = File::create(&path).with_context(|| format!("creating {path:?}"))?;
+        Ok(Staged {
+            key: key.to_owned(),
+            path,
+            file: Some(file),
+        })
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// The number of bytes written so far.
+    pub fn len(&self) -> std::io::Result<u64> {
+        Ok(self.file.as_ref().unwrap().metadata()?.len())
+    }
+
+    /// Hand over the staged file, e.g. by renaming it into place. The caller is responsible for
+    /// it from now on.
+    pub fn finish(mut self) -> Result<PathBuf> {
+        let mut file = self.file.take().unwrap();
+        file.flush()?;
+        file.sync_all()?;
+        Ok(std::mem::take(&mut self.path))
+    }
+}
+
+impl Write for Staged {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.as_mut().unwrap().flush()
+    }
+}
+
+impl Drop for Staged {
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Copy `keys` from one storage to another.
+pub fn copy(from: &dyn Storage, to: &dyn Storage, keys: &[String]) -> Result<()> {
+    let mut staged = Vec::new();
+    for key in keys {
+        let mut reader = from
+            .read(key)?
+            .with_context(|| format!("{key} disappeared while copying it"))?;
+        let mut writer = to.write(key)?;
+        std::io::copy(&mut reader, &mut writer)?;
+        staged.push(writer);
+    }
+    to.commit(staged)
+}
@@ -0,0 +1,186 @@
+use std::collections::BTreeSet;
+use std::io::Read;
+
+use anyhow::{Context, Result};
+
+use super::{copy, Staged, Storage};
+
+/// A stack of storages, fastest first (e.g. memory → local → remote).
+///
+/// Reads are served by the first tier that has the object, and copied into the tiers above it.
+/// Writes go to the first tier, and are copied to the others on commit.
+///
+/// The first tier is authoritative, so errors from it are returned. The others are only caches or
+/// ways of sharing, so errors from them are logged and otherwise ignored.
+#[derive(Debug)]
+pub struct Tiered {
+    tiers: Vec<Box<dyn Storage>>,
+}
+
+impl Tiered {
+    pub fn new(tiers: Vec<Box<dyn Storage>>) -> Self {
+        assert!(!tiers.is_empty(), "Tiered storage needs at least one tier");
+        Tiered { tiers }
+    }
+
+    fn first(&self) -> &dyn Storage {
+        self.tiers[0].as_ref()
+    }
+
+    fn others(&self) -> impl Iterator<Item = &dyn Storage> {
+        self.tiers[1..].iter().map(|tier| tier.as_ref())
+    }
+
+    /// Copy `reader` into the first tier, and from there into the other tiers above `found_in`.
+    fn promote(&self, key: &str, mut reader: Box<dyn Read + Send>, found_in: usize) -> Result<()> {
+        let mut staged = self.first().write(key)?;
+        std::io::copy(&mut reader, &mut staged)?;
+        self.first().commit(vec![staged])?;
+        for tier in self.tiers[1..found_in].iter() {
+            if let Err(e) = copy(self.first(), tier.as_ref(), &[key.to_owned()]) {
+                log::warn!("failed to copy {key} into {tier:?}: {e:#}");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Storage for Tiered {
+    fn has(&self, key: &str) -> Result<bool> {
+        if self.first().has(key)? {
+            return Ok(true);
+        }
+        for tier in self.others() {
+            match tier.has(key) {
+                Ok(true) => return Ok(true),
+                Ok(false) => {}
+                Err(e) => log::warn!("treating {key} as missing from {tier:?}: {e:#}"),
+            }
+        }
+        Ok(false)
+    }
+
+    fn read(&self, key: &str) -> Result<Option<Box<dyn Read + Send>>> {
+        if let Some(reader) = self.first().read(key)? {
+            return Ok(Some(reader));
+        }
+        for (i, tier) in self.tiers.iter().enumerate().skip(1) {
+            let reader = match tier.read(key) {
+                Ok(Some(reader)) => reader,
+                Ok(None) => continue,
+                Err(e) => {
+                    log::warn!("treating {key} as missing from {tier:?}: {e:#}");
+                    continue;
+                }
+            };
+            self.promote(key, reader, i)
+                .with_context(|| format!("caching {key} from {tier:?}"))?;
+            return self.first().read(key);
+        }
+        Ok(None)
+    }
+
+    fn write(&self, key: &str) -> Result<Staged> {
+        self.first().write(key)
+    }
+
+    fn commit(&self, staged: Vec<Staged>) -> Result<()> {
+        let keys: Vec<String> = staged.iter().map(|s| s.key().to_owned()).collect();
+        self.first().commit(staged)?;
+        for tier in self.others() {
+            if let Err(e) = copy(self.first(), tier, &keys) {
+                log::warn!("failed to copy {keys:?} into {tier:?}: {e:#}");
+            }
+        }
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let mut keys: BTreeSet<String> = self.first().list()?.into_iter().collect();
+        for tier in self.others() {
+            match tier.list() {
+                Ok(tier_keys) => keys.extend(tier_keys),
+                Err(e) => log::warn!("not listing {tier:?}: {e:#}"),
+            }
+        }
+        Ok(keys.into_iter().collect())
+    }
+
+    fn abandoned(&self) -> Result<Vec<String>> {
+        let mut names: BTreeSet<String> = self.first().abandoned()?.into_iter().collect();
+        for tier in self.others() {
+            match tier.abandoned() {
+                Ok(tier_names) => names.extend(tier_names),
+                Err(e) => log::warn!("not checking {tier:?} for abandoned objects: {e:#}"),
+            }
+        }
+        Ok(names.into_iter().collect())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.first().delete(key)?;
+        for tier in self.others() {
+            if let Err(e) = tier.delete(key) {
+                log::warn!("failed to delete {key} from {tier:?}: {e:#}");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    fn read_to_vec(storage: &dyn Storage, key: &str) -> Option<Vec<u8>> {
+        let mut contents = Vec::new();
+        storage
+            .read(key)
+            .unwrap()?
+            .read_to_end(&mut contents)
+            .unwrap();
+        Some(contents)
+    }
+
+    #[test]
+    fn reads_through_and_writes_everywhere() -> Result<()> {
+        let memory = MemoryStorage::default();
+        let local = MemoryStorage::default();
+        let remote = MemoryStorage::default();
+        let tiered = Tiered::new(vec![
+            Box::new(memory.clone()),
+            Box::new(local.clone()),
+            Box::new(remote.clone()),
+        ]);
+
+        remote.insert("a.tar", b"from the remote");
+        assert!(tiered.has("a.tar")?);
+        assert!(!memory.has("a.tar")?);
+        assert_eq!(
+            read_to_vec(&tiered, "a.tar").as_deref(),
+            Some(&b"from the remote"[..])
+        );
+        assert_eq!(
+            memory.get("a.tar").as_deref(),
+            Some(&b"from the remote"[..])
+        );
+        assert_eq!(local.get("a.tar").as_deref(), Some(&b"from the remote"[..]));
+
+        let mut staged = tiered.write("b.tar")?;
+        staged.write_all(b"built here")?;
+        assert!(!tiered.has("b.tar")?);
+        tiered.commit(vec![staged])?;
+        for tier in [&memory, &local, &remote] {
+            assert_eq!(tier.get("b.tar").as_deref(), Some(&b"built here"[..]));
+        }
+
+        assert_eq!(tiered.list()?, vec!["a.tar", "b.tar"]);
+        tiered.delete("a.tar")?;
+        assert!(!tiered.has("a.tar")?);
+        assert_eq!(read_to_vec(&tiered, "missing.tar"), None);
+        Ok(())
+    }
+}
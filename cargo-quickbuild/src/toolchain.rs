@@ -0,0 +1,93 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use cargo::core::compiler::{CompileKind, RustcTargetData};
+use serde::{Deserialize, Serialize};
+
+use crate::util::command::command;
+
+/// The compiler that a layer is built with. Layers are only valid for the exact compiler that
+/// produced them, so this is folded into every `PackageDescription`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Toolchain {
+    pub rustc_version: String,
+    pub rustc_commit_hash: String,
+    pub host: String,
+    pub target: String,
+    pub cargo_version: String,
+    /// Where the toolchain lives. Not part of the fingerprint, because it is different for each
+    /// machine.
+    #[serde(skip)]
+    sysroot: PathBuf,
+}
+
+impl Toolchain {
+    pub fn new(target_data: &RustcTargetData, kind: CompileKind) -> Result<Self> {
+        let rustc = &target_data.rustc;
+        let rustc_commit_hash = rustc
+            .verbose_version
+            .lines()
+            .find_map(|line| line.strip_prefix("commit-hash: "))
+            .with_context(|| {
+                format!(
+                    "no commit-hash in `rustc -vV` output:\n{}",
+                    rustc.verbose_version
+                )
+            })?
+            .to_string();
+        let sysroot = target_data.info(CompileKind::Host).sysroot.clone();
+
+        let mut toolchain = Self {
+            rustc_version: rustc.version.to_string(),
+            rustc_commit_hash,
+            host: rustc.host.to_string(),
+            target: target_data.short_name(&kind).to_string(),
+            cargo_version: String::new(),
+            sysroot,
+        };
+        let output = command([toolchain.cargo(), "--version".into()]).output()?;
+        anyhow::ensure!(
+            output.status.success(),
+            "`cargo --version` failed: {output:?}"
+        );
+        toolchain.cargo_version = String::from_utf8(output.stdout)?.trim().to_string();
+
+        Ok(toolchain)
+    }
+
+    /// A one-line summary, suitable for putting in a comment at the top of a Cargo.toml.
+    pub fn fingerprint(&self) -> String {
+        let Self {
+            rustc_version,
+            rustc_commit_hash,
+            host,
+            target,
+            cargo_version,
+            sysroot: _,
+        } = self;
+        format!("rustc {rustc_version} ({rustc_commit_hash}) host={host} target={target} {cargo_version}")
+    }
+
+    /// The `cargo` binary that belongs to this toolchain.
+    ///
+    /// We can't just run `cargo` from $PATH, because rustup picks the toolchain based on the
+    /// current directory (rust-toolchain.toml), and our scratch directories live elsewhere.
+    pub fn cargo(&self) -> PathBuf {
+        let cargo = self.sysroot.join("bin/cargo");
+        if cargo.exists() {
+            cargo
+        } else {
+            PathBuf::from("cargo")
+        }
+    }
+
+    /// The `rustc` binary that belongs to this toolchain. See `cargo()`.
+    pub fn rustc(&self) -> PathBuf {
+        let rustc = self.sysroot.join("bin/rustc");
+        if rustc.exists() {
+            rustc
+        } else {
+            PathBuf::from("rustc")
+        }
+    }
+}
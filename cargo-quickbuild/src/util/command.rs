@@ -1,5 +1,5 @@
 use std::ffi::OsStr;
-use std::io::{Error, ErrorKind, Read, Write};
+use std::io::{Error, Read, Write};
 use std::process::{Command, Stdio};
 use std::thread;
 
@@ -32,10 +32,7 @@ impl CommandExt for Command {
         if ecode.success() {
             Ok(())
         } else {
-            Err(Error::new(
-                ErrorKind::Other,
-                "command {self:?} failed: {ecode:?}",
-            ))
+            Err(Error::other(format!("command {self:?} failed: {ecode:?}")))
         }
     }
 
@@ -69,10 +66,7 @@ impl CommandExt for Command {
         if ecode.success() {
             Ok(())
         } else {
-            Err(Error::new(
-                ErrorKind::Other,
-                "command {self:?} failed: {ecode:?}",
-            ))
+            Err(Error::other(format!("command {self:?} failed: {ecode:?}")))
         }
     }
 }
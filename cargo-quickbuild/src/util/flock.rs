@@ -0,0 +1,69 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+/// An advisory lock on a file, held until this is dropped.
+///
+/// The lock belongs to the open file, so the kernel releases it if the process dies, which
+/// makes it safe to use for detecting abandoned work.
+#[derive(Debug)]
+pub struct Flock {
+    _file: File,
+}
+
+impl Flock {
+    /// Lock `path`, creating it if needed, and wait for anyone else who is holding it.
+    pub fn exclusive(path: &Path) -> Result<Self> {
+        Self::wait(path, libc::LOCK_EX)
+    }
+
+    /// Lock `path` in shared mode, creating it if needed. Any number of shared locks can be held
+    /// at once, so this only waits for anyone who is holding it exclusively.
+    pub fn shared(path: &Path) -> Result<Self> {
+        Self::wait(path, libc::LOCK_SH)
+    }
+
+    fn wait(path: &Path, operation: libc::c_int) -> Result<Self> {
+        let file = open(path)?;
+        if let Err(e) = flock(&file, operation | libc::LOCK_NB) {
+            if e.kind() != io::ErrorKind::WouldBlock {
+                return Err(e).with_context(|| format!("locking {path:?}"));
+            }
+            log::info!("waiting for lock on {path:?}");
+            flock(&file, operation).with_context(|| format!("locking {path:?}"))?;
+        }
+        Ok(Flock { _file: file })
+    }
+
+    /// Lock `path`, creating it if needed, or return `None` if someone else is holding it.
+    pub fn try_exclusive(path: &Path) -> Result<Option<Self>> {
+        let file = open(path)?;
+        match flock(&file, libc::LOCK_EX | libc::LOCK_NB) {
+            Ok(()) => Ok(Some(Flock { _file: file })),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("locking {path:?}")),
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<File> {
+    File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| format!("opening lock file {path:?}"))
+}
+
+fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+    // SAFETY: the file descriptor is valid for as long as `file` is alive.
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
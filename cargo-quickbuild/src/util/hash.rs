@@ -0,0 +1,21 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use crypto_hash::{Algorithm, Hasher};
+
+/// The hex-encoded SHA-256 of everything that `reader` produces.
+pub fn sha256_reader(mut reader: impl Read) -> io::Result<String> {
+    let mut hasher = Hasher::new(Algorithm::SHA256);
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(hex(&hasher.finish()))
+}
+
+/// The hex-encoded SHA-256 of a file's contents.
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    sha256_reader(File::open(path)?)
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
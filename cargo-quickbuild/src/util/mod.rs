@@ -1,2 +1,6 @@
 pub mod command;
-pub mod fixed_tempdir;
+pub mod flock;
+pub mod hash;
+pub mod reflink;
+pub mod scratch_dir;
+pub mod units;
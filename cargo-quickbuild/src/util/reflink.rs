@@ -0,0 +1,37 @@
+use std::io;
+use std::path::Path;
+
+/// Make `to` a copy-on-write clone of `from`, sharing its storage until either of them is written
+/// to. This only works on filesystems that support it (e.g. btrfs, XFS), and fails with an error
+/// on others.
+///
+/// Like `std::fs::copy()`, this doesn't preserve mtimes.
+#[cfg(target_os = "linux")]
+pub fn reflink(from: &Path, to: &Path) -> io::Result<()> {
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+
+    // _IOW(0x94, 9, int), from linux/fs.h.
+    const FICLONE: libc::c_ulong = 0x40049409;
+
+    let source = File::open(from)?;
+    let dest = File::create(to)?;
+    // SAFETY: both file descriptors are valid for as long as the files are alive.
+    if unsafe { libc::ioctl(dest.as_raw_fd(), FICLONE, source.as_raw_fd()) } == 0 {
+        dest.set_permissions(source.metadata()?.permissions())?;
+        Ok(())
+    } else {
+        let e = io::Error::last_os_error();
+        drop(dest);
+        let _ = std::fs::remove_file(to);
+        Err(e)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn reflink(_from: &Path, _to: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reflinks are only supported on linux",
+    ))
+}
@@ -0,0 +1,222 @@
+use std::fs::remove_dir_all;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use memchr::memmem;
+use tempdir::TempDir;
+
+use crate::util::flock::Flock;
+
+const PREFIX: &str = "scratch";
+
+/// How much of a file `PathRemap::is_needed()` reads at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Where scratch dirs live. This deliberately ignores `$TMPDIR`: the stable path is derived from
+/// it, and ends up in layers that are shared between machines (see `remote`), so it has to be
+/// the same everywhere.
+const ROOT: &str = "/tmp/cargo-quickbuild";
+
+/// A uniquely-named directory under `/tmp/cargo-quickbuild/`, so that concurrent builds (in this
+/// process or in others) don't trample each other.
+///
+/// Build outputs contain absolute paths (build script `OUT_DIR`s, dep-info files, debuginfo), so
+/// we pretend that every build happened in the same place: `remap_to_stable()` rewrites our
+/// unique path to a fixed path of the same length before files go into a layer, and
+/// `remap_from_stable()` does the opposite when unpacking into a scratch dir.
+///
+/// Each scratch dir is locked for as long as it is alive. Any unlocked ones that we find were left
+/// behind by a process that died, and get cleaned up.
+pub struct ScratchDir {
+    // Field order matters: the directory must be removed before the lock is released.
+    dir: TempDir,
+    _lock: Flock,
+    stable_path: PathBuf,
+}
+
+impl ScratchDir {
+    pub fn new() -> Result<Self> {
+        let root = PathBuf::from(ROOT);
+        std::fs::create_dir_all(&root).with_context(|| format!("making {root:?}"))?;
+        // cargo sees the canonical path (e.g. /private/tmp on macOS), so that's what we need to
+        // remap.
+        let root = root.canonicalize()?;
+
+        // Hold the root lock while creating a scratch dir and taking its lock, so that sweep()
+        // never sees a half-created one.
+        let _root_lock = Flock::exclusive(&root.join(".lock"))?;
+        sweep(&root);
+        let dir = TempDir::new_in(&root, PREFIX)
+            .with_context(|| format!("making tempdir in {root:?}"))?;
+        let lock = Flock::exclusive(&dir.path().join(".lock"))?;
+
+        let unique_len = dir.path().file_name().unwrap().len() - PREFIX.len() - 1;
+        let stable_path = root.join(format!("{PREFIX}.{}", "0".repeat(unique_len)));
+        assert_eq!(stable_path.as_os_str().len(), dir.path().as_os_str().len());
+
+        Ok(ScratchDir {
+            dir,
+            _lock: lock,
+            stable_path,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Rewrites paths inside this scratch dir into the stable location, for archiving.
+    pub fn remap_to_stable(&self) -> PathRemap {
+        PathRemap::new(self.path(), &self.stable_path)
+    }
+
+    /// Rewrites paths in the stable location into this scratch dir, for unpacking.
+    pub fn remap_from_stable(&self) -> PathRemap {
+        PathRemap::new(&self.stable_path, self.path())
+    }
+}
+
+/// Remove any scratch dirs whose owner has gone away.
+fn sweep(root: &Path) {
+    let entries = match std::fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::warn!("not sweeping {root:?}: {e}");
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_scratch_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false)
+            && entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(&format!("{PREFIX}."));
+        if !is_scratch_dir {
+            continue;
+        }
+        let lock_path = path.join(".lock");
+        // Scratch dirs are created and locked while holding the root lock, so a missing lock file
+        // means that the process died in between.
+        let stale =
+            !lock_path.exists() || matches!(Flock::try_exclusive(&lock_path), Ok(Some(_lock)));
+        if stale {
+            log::info!("removing stale scratch dir {path:?}");
+            if let Err(e) = remove_dir_all(&path) {
+                log::warn!("failed to remove {path:?}: {e}");
+            }
+        }
+    }
+}
+
+/// A byte-for-byte substitution of one path for another of the same length.
+///
+/// Keeping the length the same means that it is safe to apply this to binary files too.
+#[derive(Debug, Clone)]
+pub struct PathRemap {
+    from: Vec<u8>,
+    to: Vec<u8>,
+}
+
+impl PathRemap {
+    fn new(from: &Path, to: &Path) -> Self {
+        let from = from.as_os_str().as_bytes().to_vec();
+        let to = to.as_os_str().as_bytes().to_vec();
+        assert_eq!(from.len(), to.len(), "path remapping must preserve lengths");
+        PathRemap { from, to }
+    }
+
+    /// Replace every occurrence of `from` in `contents`. Returns whether anything was replaced.
+    pub fn apply(&self, contents: &mut [u8]) -> bool {
+        if self.from == self.to {
+            return false;
+        }
+        // Matches don't overlap, so replacing them doesn't affect where the others are.
+        let matches: Vec<usize> = memmem::find_iter(contents, &self.from).collect();
+        for &at in &matches {
+            contents[at..at + self.to.len()].copy_from_slice(&self.to);
+        }
+        !matches.is_empty()
+    }
+
+    /// Whether `apply()` would change anything that `reader` produces, without holding it all in
+    /// memory. Everything that is read is also written to `tee` (e.g. to hash it).
+    pub fn is_needed(&self, mut reader: impl Read, mut tee: impl Write) -> io::Result<bool> {
+        let finder = memmem::Finder::new(&self.from);
+        // Keep the end of each chunk, in case a match straddles two of them.
+        let overlap = self.from.len().saturating_sub(1);
+        let mut buf = vec![0; overlap + CHUNK_SIZE];
+        let mut kept = 0;
+        let mut found = false;
+        loop {
+            let n = match reader.read(&mut buf[kept..]) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            tee.write_all(&buf[kept..kept + n])?;
+            let filled = kept + n;
+            found = found || (self.from != self.to && finder.find(&buf[..filled]).is_some());
+            kept = filled.min(overlap);
+            buf.copy_within(filled - kept..filled, 0);
+        }
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remaps_every_occurrence_in_place() {
+        let remap = PathRemap::new(
+            Path::new("/tmp/a/scratch.x1y2z3"),
+            Path::new("/tmp/a/scratch.000000"),
+        );
+        let mut contents = b"\0/tmp/a/scratch.x1y2z3/target\0-L/tmp/a/scratch.x1y2z3\xff".to_vec();
+        let len = contents.len();
+        assert!(remap.apply(&mut contents));
+        assert_eq!(
+            contents,
+            b"\0/tmp/a/scratch.000000/target\0-L/tmp/a/scratch.000000\xff"
+        );
+        assert_eq!(contents.len(), len);
+        assert!(!remap.apply(&mut contents));
+    }
+
+    #[test]
+    fn finds_paths_that_straddle_chunks() -> io::Result<()> {
+        let remap = PathRemap::new(
+            Path::new("/tmp/a/scratch.x1y2z3"),
+            Path::new("/tmp/a/scratch.000000"),
+        );
+        let mut contents = vec![0; CHUNK_SIZE - 5];
+        assert!(!remap.is_needed(contents.as_slice(), io::sink())?);
+
+        contents.extend_from_slice(b"/tmp/a/scratch.x1y2z3");
+        contents.resize(3 * CHUNK_SIZE, 0);
+        let mut tee = Vec::new();
+        assert!(remap.is_needed(contents.as_slice(), &mut tee)?);
+        assert_eq!(tee, contents);
+        Ok(())
+    }
+
+    #[test]
+    fn stable_path_does_not_depend_on_the_scratch_dir() -> Result<()> {
+        let (a, b) = (ScratchDir::new()?, ScratchDir::new()?);
+        assert_ne!(a.path(), b.path());
+        assert_eq!(a.stable_path, b.stable_path);
+        assert!(a.stable_path.starts_with(Path::new(ROOT).canonicalize()?));
+
+        let mut path = a.path().as_os_str().as_bytes().to_vec();
+        assert!(a.remap_to_stable().apply(&mut path));
+        assert_eq!(path, b.stable_path.as_os_str().as_bytes());
+        assert!(b.remap_from_stable().apply(&mut path));
+        assert_eq!(path, b.path().as_os_str().as_bytes());
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+const SIZE_UNITS: &[(&str, u64)] = &[("G", 1 << 30), ("M", 1 << 20), ("K", 1 << 10), ("B", 1)];
+
+const DURATION_UNITS: &[(&str, u64)] = &[
+    ("w", 7 * 24 * 60 * 60),
+    ("d", 24 * 60 * 60),
+    ("h", 60 * 60),
+    ("m", 60),
+    ("s", 1),
+];
+
+/// Parse a size like `100M`, `2G` or `4096` (bytes). Units are powers of 1024.
+pub fn parse_size(s: &str) -> Result<u64> {
+    parse_with_units(s, SIZE_UNITS, "B").with_context(|| {
+        format!("invalid size {s:?} (expected a number with an optional K, M or G suffix)")
+    })
+}
+
+/// Parse a duration like `30d`, `12h` or `90s`. A bare number is in seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    parse_with_units(s, DURATION_UNITS, "s")
+        .map(Duration::from_secs)
+        .with_context(|| {
+            format!("invalid duration {s:?} (expected a number with an s, m, h, d or w suffix)")
+        })
+}
+
+fn parse_with_units(s: &str, units: &[(&str, u64)], default_unit: &str) -> Result<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let unit = match unit.trim() {
+        "" => default_unit,
+        unit => unit,
+    };
+    let (_, multiplier) = units
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(unit))
+        .with_context(|| format!("unknown unit {unit:?}"))?;
+    let number: u64 = number.parse()?;
+    number
+        .checked_mul(*multiplier)
+        .context("too big to represent")
+}
+
+/// Format a size with the largest unit that keeps it at least 1, e.g. `1.5M`.
+pub fn format_size(size: u64) -> String {
+    for (name, multiplier) in SIZE_UNITS {
+        if size >= *multiplier && *multiplier > 1 {
+            return format!("{:.1}{name}", size as f64 / *multiplier as f64);
+        }
+    }
+    format!("{size}B")
+}
+
+/// Format a duration roughly, in its largest whole unit, e.g. `3d`.
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    for (name, multiplier) in DURATION_UNITS {
+        if seconds >= *multiplier {
+            return format!("{}{name}", seconds / multiplier);
+        }
+    }
+    String::from("0s")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_formats() -> Result<()> {
+        assert_eq!(parse_size("4096")?, 4096);
+        assert_eq!(parse_size("10K")?, 10 * 1024);
+        assert_eq!(parse_size("2g")?, 2 << 30);
+        assert!(parse_size("2X").is_err());
+        assert!(parse_size("M").is_err());
+        assert_eq!(parse_duration("90")?, Duration::from_secs(90));
+        assert_eq!(parse_duration("12h")?, Duration::from_secs(12 * 60 * 60));
+        assert_eq!(
+            parse_duration("2w")?,
+            Duration::from_secs(14 * 24 * 60 * 60)
+        );
+
+        assert_eq!(format_size(100), "100B");
+        assert_eq!(format_size(1536 * 1024), "1.5M");
+        assert_eq!(format_duration(Duration::from_secs(59)), "59s");
+        assert_eq!(
+            format_duration(Duration::from_secs(3 * 24 * 60 * 60 + 5)),
+            "3d"
+        );
+        Ok(())
+    }
+}